@@ -0,0 +1,43 @@
+use std::env;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// Tried in order. Each reads the text to copy from stdin.
+const CLIPBOARD_TOOLS: &[(&str, &[&str])] = &[
+    ("pbcopy", &[]),
+    ("wl-copy", &[]),
+    ("xclip", &["-selection", "clipboard"]),
+    ("xsel", &["--clipboard", "--input"]),
+];
+
+// Copies text with whichever clipboard tool is installed, for when it's too
+// big to send with OSC 52. Only tried when there's a desktop here to have a
+// clipboard, since over ssh it would be the wrong machine's. Gives the
+// tool's name.
+pub fn copy_with_tool(text: &str) -> Option<&'static str> {
+    let local = cfg!(target_os = "macos")
+        || env::var_os("WAYLAND_DISPLAY").is_some()
+        || env::var_os("DISPLAY").is_some();
+    if !local || env::var_os("SSH_TTY").is_some() { return None }
+    CLIPBOARD_TOOLS.iter()
+        .find(|&&(tool, args)| run(tool, args, text))
+        .map(|&(tool, _)| tool)
+}
+
+fn run(tool: &str, args: &[&str], text: &str) -> bool {
+    // Anything it prints would land on top of the editor
+    let child = Command::new(tool)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(_) => return false,
+    };
+    let written = child.stdin.take().unwrap().write_all(text.as_bytes()).is_ok();
+    // xclip and friends fork to hold on to the selection, so this doesn't
+    // wait for long
+    child.wait().is_ok_and(|status| status.success()) && written
+}
@@ -0,0 +1,251 @@
+use editor::cursor::Cursor;
+use editor::file_format::LineEnding;
+use util;
+
+// Inserting or removing a line only shifts the lines in its own chunk, so
+// chunks are split once they grow past twice this size.
+const CHUNK_SIZE: usize = 1024;
+
+// Lines that haven't been touched since the file was loaded are spans into
+// the original text; only edited lines own their contents.
+enum Line {
+    Original(usize, usize),
+    Edited(String),
+}
+
+pub struct Buffer {
+    original: String,
+    chunks: Vec<Vec<Line>>,
+    // The number of the first line in each chunk, for finding lines quickly
+    starts: Vec<usize>,
+    len: usize,
+}
+
+impl Buffer {
+    pub fn new() -> Self {
+        Buffer {
+            original: String::new(),
+            chunks: Vec::new(),
+            starts: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn from_string(original: String, line_ending: LineEnding) -> Self {
+        let mut buffer = Buffer::new();
+        buffer.original = original;
+        let mut spans = Vec::new();
+        let mut start = 0;
+        for (i, _) in buffer.original.match_indices('\n') {
+            let end = if line_ending == LineEnding::CrLf { i - 1 } else { i };
+            spans.push((start, end));
+            start = i + 1;
+        }
+        if start < buffer.original.len() {
+            spans.push((start, buffer.original.len()));
+        }
+
+        buffer.len = spans.len();
+        buffer.chunks = spans.chunks(CHUNK_SIZE)
+            .map(|chunk| chunk.iter().map(|&(s, e)| Line::Original(s, e)).collect())
+            .collect();
+        buffer.starts = (0..buffer.chunks.len()).map(|c| c * CHUNK_SIZE).collect();
+        buffer
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn line(&self, y: usize) -> &str {
+        let (c, i) = self.locate(y);
+        self.line_str(&self.chunks[c][i])
+    }
+
+    pub fn lines<'a>(&'a self) -> impl Iterator<Item=&'a str> + 'a {
+        self.chunks.iter()
+            .flat_map(|chunk| chunk.iter())
+            .map(move |line| self.line_str(line))
+    }
+
+    // Returns the position just past the inserted text.
+    pub fn insert(&mut self, at: Cursor, text: &str) -> Cursor {
+        if at.y == self.len { self.insert_line(at.y, String::new()) }
+        let mut head = self.line(at.y).to_string();
+        let tail = util::safe_split_off(&mut head, at.x);
+
+        let mut new_lines = text.split('\n').map(String::from).collect::<Vec<String>>();
+        new_lines[0] = head + &new_lines[0];
+        let last = new_lines.len() - 1;
+        let end = Cursor { x: new_lines[last].len(), y: at.y + last };
+        new_lines[last].push_str(&tail);
+
+        let mut new_lines = new_lines.into_iter();
+        self.set_line(at.y, new_lines.next().unwrap());
+        for (i, line) in new_lines.enumerate() {
+            self.insert_line(at.y + 1 + i, line);
+        }
+        end
+    }
+
+    // Returns the deleted text.
+    pub fn delete(&mut self, start: Cursor, end: Cursor) -> String {
+        let deleted = self.text_between(start, end);
+        let mut joined = self.line(start.y).to_string();
+        joined.truncate(start.x);
+        let mut tail = self.line(end.y).to_string();
+        joined.push_str(&util::safe_split_off(&mut tail, end.x));
+        for _ in start.y..end.y { self.remove_line(start.y + 1); }
+        self.set_line(start.y, joined);
+        deleted
+    }
+
+    pub fn text_between(&self, start: Cursor, end: Cursor) -> String {
+        if start.y == end.y {
+            return self.line(start.y)[start.x..end.x].to_string()
+        }
+        let mut text = self.line(start.y)[start.x..].to_string();
+        for y in start.y + 1..end.y {
+            text.push('\n');
+            text.push_str(self.line(y));
+        }
+        text.push('\n');
+        text.push_str(&self.line(end.y)[..end.x]);
+        text
+    }
+
+    fn line_str<'a>(&'a self, line: &'a Line) -> &'a str {
+        match *line {
+            Line::Original(start, end) => &self.original[start..end],
+            Line::Edited(ref s) => s,
+        }
+    }
+
+    // Finds the chunk holding line y and its index within that chunk. Asking
+    // for the line just past the end gives the end of the last chunk.
+    fn locate(&self, y: usize) -> (usize, usize) {
+        if y > self.len || self.chunks.is_empty() { panic!("line {} out of range", y) }
+        // Chunks are never left empty, so no two start on the same line
+        let c = match self.starts.binary_search(&y) {
+            Ok(c) => c,
+            Err(c) => c - 1,
+        };
+        (c, y - self.starts[c])
+    }
+
+    fn set_line(&mut self, y: usize, s: String) {
+        let (c, i) = self.locate(y);
+        self.chunks[c][i] = Line::Edited(s);
+    }
+
+    fn insert_line(&mut self, at: usize, s: String) {
+        if self.chunks.is_empty() {
+            self.chunks.push(Vec::new());
+            self.starts.push(0);
+        }
+        let (c, i) = self.locate(at);
+        self.chunks[c].insert(i, Line::Edited(s));
+        for start in &mut self.starts[c + 1..] { *start += 1 }
+        if self.chunks[c].len() > 2 * CHUNK_SIZE {
+            let rest = self.chunks[c].split_off(CHUNK_SIZE);
+            self.chunks.insert(c + 1, rest);
+            self.starts.insert(c + 1, self.starts[c] + CHUNK_SIZE);
+        }
+        self.len += 1;
+    }
+
+    fn remove_line(&mut self, at: usize) {
+        let (c, i) = self.locate(at);
+        self.chunks[c].remove(i);
+        for start in &mut self.starts[c + 1..] { *start -= 1 }
+        if self.chunks[c].is_empty() {
+            self.chunks.remove(c);
+            self.starts.remove(c);
+        }
+        self.len -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(text: &str) -> Buffer {
+        Buffer::from_string(text.to_string(), LineEnding::Lf)
+    }
+
+    fn lines(buffer: &Buffer) -> Vec<&str> {
+        buffer.lines().collect()
+    }
+
+    #[test]
+    fn delete_after_wide_character() {
+        let mut b = buffer("中ab");
+        let deleted = b.delete(Cursor { x: 3, y: 0 }, Cursor { x: 4, y: 0 });
+        assert_eq!(deleted, "a");
+        assert_eq!(lines(&b), ["中b"]);
+    }
+
+    #[test]
+    fn delete_after_accented_character() {
+        let mut b = buffer("éa");
+        b.delete(Cursor { x: 2, y: 0 }, Cursor { x: 3, y: 0 });
+        assert_eq!(lines(&b), ["é"]);
+    }
+
+    #[test]
+    fn delete_joins_non_ascii_lines() {
+        let mut b = buffer("añ\n中x\nz");
+        let deleted = b.delete(Cursor { x: 3, y: 0 }, Cursor { x: 3, y: 1 });
+        assert_eq!(deleted, "\n中");
+        assert_eq!(lines(&b), ["añx", "z"]);
+    }
+
+    #[test]
+    fn insert_after_non_ascii() {
+        let mut b = buffer("中b");
+        let end = b.insert(Cursor { x: 3, y: 0 }, "é");
+        assert_eq!(end, Cursor { x: 5, y: 0 });
+        assert_eq!(lines(&b), ["中éb"]);
+    }
+
+    #[test]
+    fn insert_lines_after_non_ascii() {
+        let mut b = buffer("ñb");
+        let end = b.insert(Cursor { x: 2, y: 0 }, "x\n中");
+        assert_eq!(end, Cursor { x: 3, y: 1 });
+        assert_eq!(lines(&b), ["ñx", "中b"]);
+    }
+
+    #[test]
+    fn lines_stay_in_place_across_chunks() {
+        let text = (0..5 * CHUNK_SIZE).map(|y| y.to_string()).collect::<Vec<_>>().join("\n");
+        let mut b = buffer(&text);
+        // Enough new lines to split a chunk, then take them out again along
+        // with the whole of the next chunk
+        let middle = Cursor { x: 0, y: CHUNK_SIZE + 3 };
+        let end = b.insert(middle, &"x\n".repeat(2 * CHUNK_SIZE));
+        assert_eq!(b.line(CHUNK_SIZE + 3), "x");
+        assert_eq!(b.line(3 * CHUNK_SIZE + 3), (CHUNK_SIZE + 3).to_string());
+        assert_eq!(b.line(b.len() - 1), (5 * CHUNK_SIZE - 1).to_string());
+        b.delete(middle, Cursor { x: 0, y: end.y + CHUNK_SIZE });
+        assert_eq!(b.len(), 4 * CHUNK_SIZE);
+        for y in (0..b.len()).step_by(97) {
+            let expected = if y < CHUNK_SIZE + 3 { y } else { y + CHUNK_SIZE };
+            assert_eq!(b.line(y), expected.to_string());
+        }
+    }
+
+    #[test]
+    fn delete_undoes_insert() {
+        let mut b = buffer("é中\nñ");
+        let at = Cursor { x: 2, y: 0 };
+        let end = b.insert(at, "a\nb");
+        assert_eq!(b.delete(at, end), "a\nb");
+        assert_eq!(lines(&b), ["é中", "ñ"]);
+    }
+}
@@ -0,0 +1,95 @@
+use std::io;
+
+// Windows-1252 code points for bytes 0x80-0x9F. The five bytes it leaves
+// undefined map to the matching C1 control, so every byte decodes to
+// something and encodes back to itself.
+const CP1252_HIGH: [char; 32] = [
+    '\u{20ac}', '\u{81}',   '\u{201a}', '\u{192}',  '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}',  '\u{2030}', '\u{160}',  '\u{2039}', '\u{152}',  '\u{8d}',   '\u{17d}',  '\u{8f}',
+    '\u{90}',   '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}',  '\u{2122}', '\u{161}',  '\u{203a}', '\u{153}',  '\u{9d}',   '\u{17e}',  '\u{178}',
+];
+
+// How many bytes to look at when deciding whether a file is binary.
+const BINARY_SNIFF_LEN: usize = 8000;
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Encoding {
+    Utf8,
+    Windows1252,
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Windows1252 => "CP1252",
+        }
+    }
+
+    // Anything that isn't valid UTF-8 is read as Windows-1252, which covers
+    // Latin-1 text and round-trips arbitrary bytes unchanged.
+    pub fn decode(bytes: Vec<u8>) -> (Self, String) {
+        match String::from_utf8(bytes) {
+            Ok(text) => (Encoding::Utf8, text),
+            Err(e) => {
+                let text = e.as_bytes().iter().map(|&b| Self::decode_cp1252(b)).collect();
+                (Encoding::Windows1252, text)
+            },
+        }
+    }
+
+    pub fn encode(self, text: &str) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            Encoding::Windows1252 => {
+                text.chars().map(|c| {
+                    Self::encode_cp1252(c).ok_or_else(|| io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("'{}' can't be written as {}", c, self.name())))
+                }).collect()
+            },
+        }
+    }
+
+    fn decode_cp1252(b: u8) -> char {
+        match b {
+            0x80..=0x9f => CP1252_HIGH[(b - 0x80) as usize],
+            _ => b as char,
+        }
+    }
+
+    fn encode_cp1252(c: char) -> Option<u8> {
+        match c as u32 {
+            0x00..=0x7f | 0xa0..=0xff => Some(c as u8),
+            _ => CP1252_HIGH.iter().position(|&h| h == c).map(|i| 0x80 + i as u8),
+        }
+    }
+}
+
+pub fn looks_binary(bytes: &[u8]) -> bool {
+    bytes.iter().take(BINARY_SNIFF_LEN).any(|&b| b == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_byte_round_trips_through_cp1252() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let (encoding, text) = Encoding::decode(bytes.clone());
+        assert!(encoding == Encoding::Windows1252);
+        assert_eq!(text.chars().count(), 256);
+        assert_eq!(encoding.encode(&text).unwrap(), bytes);
+        for b in 0..=255 {
+            assert_eq!(Encoding::encode_cp1252(Encoding::decode_cp1252(b)), Some(b));
+        }
+    }
+
+    #[test]
+    fn characters_outside_cp1252_fail_to_encode() {
+        let err = Encoding::Windows1252.encode("caf\u{e9} \u{4e2d}").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,122 @@
+use editor::buffer::Buffer;
+use editor::encoding::Encoding;
+
+use std::io;
+
+const UTF8_BOM: &str = "\u{feff}";
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+        }
+    }
+
+    pub fn toggle(self) -> Self {
+        match self {
+            LineEnding::Lf => LineEnding::CrLf,
+            LineEnding::CrLf => LineEnding::Lf,
+        }
+    }
+}
+
+// Everything about how a file was laid out on disk that isn't part of the
+// text itself, so that saving writes it back the way it was found.
+#[derive(Clone, Copy)]
+pub struct FileFormat {
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
+    pub final_newline: bool,
+    pub bom: bool,
+}
+
+impl FileFormat {
+    pub fn new() -> Self {
+        FileFormat {
+            encoding: Encoding::Utf8,
+            line_ending: LineEnding::Lf,
+            final_newline: true,
+            bom: false,
+        }
+    }
+
+    // Decodes the text and strips the BOM, if any, then works out how the
+    // lines are terminated. A file only counts as CRLF if every line ending
+    // is; otherwise any stray carriage returns are kept as part of the text.
+    pub fn decode(bytes: Vec<u8>) -> (Self, String) {
+        let mut format = Self::new();
+        let (encoding, mut text) = Encoding::decode(bytes);
+        format.encoding = encoding;
+        if text.starts_with(UTF8_BOM) {
+            text.drain(..UTF8_BOM.len());
+            format.bom = true;
+        }
+        if text.is_empty() { return (format, text) }
+
+        let newlines = text.matches('\n').count();
+        if newlines > 0 && text.matches("\r\n").count() == newlines {
+            format.line_ending = LineEnding::CrLf;
+        }
+        format.final_newline = text.ends_with('\n');
+        (format, text)
+    }
+
+    pub fn encode(&self, buffer: &Buffer) -> io::Result<Vec<u8>> {
+        let mut contents = String::new();
+        if self.bom { contents.push_str(UTF8_BOM) }
+        let line_ending = self.line_ending.as_str();
+        for (y, line) in buffer.lines().enumerate() {
+            if y > 0 { contents.push_str(line_ending) }
+            contents.push_str(line);
+        }
+        if self.final_newline && !buffer.is_empty() {
+            contents.push_str(line_ending);
+        }
+        self.encoding.encode(&contents)
+    }
+
+    pub fn describe(&self) -> String {
+        let mut desc = format!("{} {}", self.encoding.name(), self.line_ending.name());
+        if self.bom { desc.push_str(" BOM") }
+        if !self.final_newline { desc.push_str(" noeol") }
+        desc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoding_and_encoding_gives_back_the_same_bytes() {
+        let inputs: &[&[u8]] = &[
+            b"one\r\ntwo\r\n",
+            b"one\ntwo\n",
+            b"one\r\ntwo\nthree\r\n",
+            b"one\ntwo",
+            b"\xef\xbb\xbfone\n",
+            b"",
+            b"\n",
+            b"\r\n\r\n",
+        ];
+        for &bytes in inputs {
+            let (format, text) = FileFormat::decode(bytes.to_vec());
+            let buffer = Buffer::from_string(text, format.line_ending);
+            assert_eq!(format.encode(&buffer).unwrap(), bytes, "{:?}", bytes);
+        }
+    }
+}
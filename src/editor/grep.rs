@@ -0,0 +1,51 @@
+use editor::cursor::Cursor;
+use editor::encoding;
+use editor::file_format::FileFormat;
+use editor::search_state::Pattern;
+use ignore::WalkBuilder;
+
+use std::fs;
+
+// Searching for something like "e" in a big tree would otherwise fill up
+// memory with results nobody is going to read.
+const GREP_LIMIT: usize = 10000;
+
+pub struct GrepMatch {
+    pub path: String,
+    pub cursor: Cursor,
+    pub line: String,
+}
+
+// Finds the first match on every line of every file under dir that git
+// would track, skipping hidden files and anything that can't be read or
+// looks binary. Also says whether it gave up at the limit.
+pub fn grep(dir: &str, pattern: &Pattern) -> (Vec<GrepMatch>, bool) {
+    let mut matches = Vec::new();
+    let walker = WalkBuilder::new(dir)
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+    for entry in walker.filter_map(|entry| entry.ok()) {
+        if !entry.file_type().is_some_and(|t| t.is_file()) { continue }
+        let bytes = match fs::read(entry.path()) {
+            Ok(bytes) => bytes,
+            Err(_) => continue,
+        };
+        if encoding::looks_binary(&bytes) { continue }
+        // Decoded the same way open_file does, so the positions line up
+        let (_, text) = FileFormat::decode(bytes);
+        let path = entry.path().to_string_lossy();
+        let path = path.strip_prefix("./").unwrap_or(&path);
+        for (y, line) in text.lines().enumerate() {
+            if let Some((x, _)) = pattern.find_at(line, 0) {
+                if matches.len() == GREP_LIMIT { return (matches, true) }
+                matches.push(GrepMatch {
+                    path: path.to_string(),
+                    cursor: Cursor { x, y },
+                    line: line.to_string(),
+                });
+            }
+        }
+    }
+    (matches, false)
+}
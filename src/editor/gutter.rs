@@ -0,0 +1,45 @@
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum LineNumbers {
+    Off,
+    Absolute,
+    // Distance from the cursor's line
+    Relative,
+    // Relative, except the cursor's line gets its absolute number
+    Hybrid,
+}
+
+impl LineNumbers {
+    pub fn next(self) -> Self {
+        match self {
+            LineNumbers::Off => LineNumbers::Absolute,
+            LineNumbers::Absolute => LineNumbers::Relative,
+            LineNumbers::Relative => LineNumbers::Hybrid,
+            LineNumbers::Hybrid => LineNumbers::Off,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LineNumbers::Off => "off",
+            LineNumbers::Absolute => "absolute",
+            LineNumbers::Relative => "relative",
+            LineNumbers::Hybrid => "hybrid",
+        }
+    }
+
+    // Columns taken up by the gutter: enough digits for the last line, and
+    // a space between the numbers and the text.
+    pub fn width(self, num_rows: usize) -> usize {
+        if self == LineNumbers::Off { return 0 }
+        num_rows.max(1).to_string().len() + 1
+    }
+
+    pub fn number(self, y: usize, cursor_y: usize) -> usize {
+        let distance = y.abs_diff(cursor_y);
+        match self {
+            LineNumbers::Relative => distance,
+            LineNumbers::Hybrid if distance > 0 => distance,
+            _ => y + 1,
+        }
+    }
+}
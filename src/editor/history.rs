@@ -0,0 +1,129 @@
+use editor::cursor::Cursor;
+use editor::file_format::LineEnding;
+
+#[derive(Clone)]
+pub enum Edit {
+    Insert { at: Cursor, text: String },
+    Delete { at: Cursor, text: String },
+    // Not part of the text, but it has to be undone along with the carriage
+    // returns that switching to CRLF takes out
+    SetLineEnding { from: LineEnding, to: LineEnding },
+}
+
+impl Edit {
+    pub fn inverse(&self) -> Self {
+        match *self {
+            Edit::Insert { at, ref text } => Edit::Delete { at, text: text.clone() },
+            Edit::Delete { at, ref text } => Edit::Insert { at, text: text.clone() },
+            Edit::SetLineEnding { from, to } => Edit::SetLineEnding { from: to, to: from },
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum EditKind {
+    Typing,
+    Deleting,
+    Other,
+}
+
+#[derive(Clone)]
+pub struct Group {
+    pub edits: Vec<Edit>,
+    pub cursor_before: Cursor,
+    pub cursor_after: Cursor,
+    kind: EditKind,
+}
+
+pub struct History {
+    undo_stack: Vec<Group>,
+    redo_stack: Vec<Group>,
+    // Depth of the undo stack when the file was last saved, or None if that
+    // state can no longer be reached by undoing or redoing.
+    saved_depth: Option<usize>,
+    // Edits made between begin_group and end_group, undone as one.
+    pending: Option<Group>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        History {
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            saved_depth: Some(0),
+            pending: None,
+        }
+    }
+
+    // Consecutive edits of the same kind that pick up where the last one left
+    // off (e.g. typing a word, or holding down Backspace) are undone together.
+    pub fn record(&mut self, edit: Edit, kind: EditKind,
+                  cursor_before: Cursor, cursor_after: Cursor) {
+        if let Some(ref mut group) = self.pending {
+            group.edits.push(edit);
+            group.cursor_after = cursor_after;
+            return;
+        }
+        self.discard_redo();
+
+        let at_saved = self.is_saved();
+        if let Some(group) = self.undo_stack.last_mut() {
+            if kind != EditKind::Other && group.kind == kind && !at_saved &&
+                group.cursor_after == cursor_before {
+                group.edits.push(edit);
+                group.cursor_after = cursor_after;
+                return;
+            }
+        }
+        self.undo_stack.push(Group {
+            edits: vec![edit],
+            cursor_before,
+            cursor_after,
+            kind,
+        });
+    }
+
+    pub fn begin_group(&mut self, cursor: Cursor) {
+        self.pending = Some(Group {
+            edits: Vec::new(),
+            cursor_before: cursor,
+            cursor_after: cursor,
+            kind: EditKind::Other,
+        });
+    }
+
+    pub fn end_group(&mut self) {
+        if let Some(group) = self.pending.take() {
+            if group.edits.is_empty() { return }
+            self.discard_redo();
+            self.undo_stack.push(group);
+        }
+    }
+
+    fn discard_redo(&mut self) {
+        if let Some(saved_depth) = self.saved_depth {
+            if saved_depth > self.undo_stack.len() { self.saved_depth = None }
+        }
+        self.redo_stack.clear();
+    }
+
+    pub fn undo(&mut self) -> Option<Group> {
+        let group = self.undo_stack.pop()?;
+        self.redo_stack.push(group.clone());
+        Some(group)
+    }
+
+    pub fn redo(&mut self) -> Option<Group> {
+        let group = self.redo_stack.pop()?;
+        self.undo_stack.push(group.clone());
+        Some(group)
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.saved_depth = Some(self.undo_stack.len());
+    }
+
+    pub fn is_saved(&self) -> bool {
+        self.saved_depth == Some(self.undo_stack.len())
+    }
+}
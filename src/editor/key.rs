@@ -1,7 +1,15 @@
+use std::str;
+
+// With bracketed paste on, the terminal puts pasted text between these.
+pub const PASTE_START: &[u8] = b"[200~";
+pub const PASTE_END: &[u8] = b"\x1b[201~";
+
 pub enum Key {
     Character(char),
     Control(char),
+    Alt(char),
     Arrow(ArrowKey),
+    ShiftArrow(ArrowKey),
     Escape,
     Backspace,
     Delete,
@@ -9,6 +17,8 @@ pub enum Key {
     End,
     PageUp,
     PageDown,
+    Paste(String),
+    Mouse(MouseEvent),
 }
 
 pub enum ArrowKey {
@@ -18,29 +28,109 @@ pub enum ArrowKey {
     Down,
 }
 
+// Where on the screen, counting from 0
+pub struct MouseEvent {
+    pub kind: MouseKind,
+    pub x: usize,
+    pub y: usize,
+}
+
+pub enum MouseKind {
+    // With the left button; the others aren't used
+    Press,
+    Drag,
+    Release,
+    WheelUp,
+    WheelDown,
+    Other,
+}
+
 impl Key {
     pub fn from_byte(byte: u8) -> Option<Self> {
         match byte {
-            0       => None,
             b'\x1b' => Some(Key::Escape),
             8 | 127 => Some(Key::Backspace),
-            1...31  => Some(Key::Control((byte | 0x40) as char)),
+            // Ctrl-Space sends 0, which comes out as Ctrl-@
+            0..=31  => Some(Key::Control((byte | 0x40) as char)),
+            // Anything past ASCII is only valid as part of a UTF-8 sequence
+            0x80..  => None,
             _       => Some(Key::Character(byte as char))
         }
     }
 
+    // A key that followed ESC on its own, which is what Alt sends.
+    pub fn with_alt(key: Option<Self>) -> Self {
+        match key {
+            Some(Key::Character(c)) => Key::Alt(c),
+            _ => Key::Escape,
+        }
+    }
+
+    // Number of bytes in the UTF-8 sequence that starts with this byte.
+    pub fn utf8_len(byte: u8) -> usize {
+        match byte {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _           => 1,
+        }
+    }
+
+    pub fn from_utf8(bytes: &[u8]) -> Option<Self> {
+        str::from_utf8(bytes).ok()
+            .and_then(|s| s.chars().next())
+            .map(Key::Character)
+    }
+
+    // Terminals send Enter in pasted text as a carriage return, like a
+    // keypress.
+    pub fn from_paste(bytes: &[u8]) -> Self {
+        let text = String::from_utf8_lossy(bytes);
+        Key::Paste(text.replace("\r\n", "\n").replace('\r', "\n"))
+    }
+
     pub fn from_escape_sequence(bytes: &[u8]) -> Self {
         match bytes {
             b"[A" => Key::Arrow(ArrowKey::Up),
             b"[B" => Key::Arrow(ArrowKey::Down),
             b"[C" => Key::Arrow(ArrowKey::Right),
             b"[D" => Key::Arrow(ArrowKey::Left),
+            b"[1;2A" => Key::ShiftArrow(ArrowKey::Up),
+            b"[1;2B" => Key::ShiftArrow(ArrowKey::Down),
+            b"[1;2C" => Key::ShiftArrow(ArrowKey::Right),
+            b"[1;2D" => Key::ShiftArrow(ArrowKey::Left),
             b"[3~"  => Key::Delete,
             b"[1~" | b"[7~" | b"[H" | b"OH" => Key::Home,
             b"[4~" | b"[8~" | b"[F" | b"OF" => Key::End,
             b"[5~" => Key::PageUp,
             b"[6~" => Key::PageDown,
+            _ if bytes.starts_with(b"[<") => {
+                Key::from_mouse_sequence(&bytes[2..]).unwrap_or(Key::Escape)
+            },
             _      => Key::Escape,
         }
     }
+
+    // SGR mouse reports look like 0;12;5M: the button and modifiers, then
+    // the column and row from 1, then M for a press or m for a release.
+    fn from_mouse_sequence(bytes: &[u8]) -> Option<Self> {
+        let (&last, params) = bytes.split_last()?;
+        let mut params = str::from_utf8(params).ok()?
+            .split(';')
+            .map(|p| p.parse::<usize>().ok());
+        let button = params.next()??;
+        let x = params.next()??.checked_sub(1)?;
+        let y = params.next()??.checked_sub(1)?;
+        // Bit 5 is set for motion and bit 6 for the wheel, and the low bits
+        // say which button
+        let kind = match (button & !0b11100, last) {
+            (64, _) => MouseKind::WheelUp,
+            (65, _) => MouseKind::WheelDown,
+            (0, b'm') => MouseKind::Release,
+            (0, b'M') => MouseKind::Press,
+            (32, b'M') => MouseKind::Drag,
+            _ => MouseKind::Other,
+        };
+        Some(Key::Mouse(MouseEvent { kind, x, y }))
+    }
 }
@@ -0,0 +1,36 @@
+// Enough to get back something cut a while ago without holding on to
+// everything ever copied.
+const KILL_RING_LEN: usize = 30;
+
+// Text that has been cut or copied, oldest first. Pasting gives the most
+// recent, and cycling straight after a paste goes back through the rest.
+pub struct KillRing {
+    entries: Vec<String>,
+    // The entry that paste gives
+    index: usize,
+}
+
+impl KillRing {
+    pub fn new() -> Self {
+        KillRing { entries: Vec::new(), index: 0 }
+    }
+
+    pub fn push(&mut self, text: String) {
+        if text.is_empty() { return }
+        self.entries.push(text);
+        if self.entries.len() > KILL_RING_LEN { self.entries.remove(0); }
+        self.index = self.entries.len() - 1;
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.entries.get(self.index).map(|s| s.as_str())
+    }
+
+    // Steps back to the entry before the current one, wrapping round to the
+    // most recent after the oldest.
+    pub fn rotate(&mut self) -> Option<&str> {
+        if self.entries.is_empty() { return None }
+        self.index = if self.index == 0 { self.entries.len() - 1 } else { self.index - 1 };
+        self.current()
+    }
+}
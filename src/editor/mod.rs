@@ -1,26 +1,49 @@
+mod buffer;
 mod cursor;
+mod encoding;
+mod file_format;
+mod grep;
+mod gutter;
+mod history;
 mod key;
+mod kill_ring;
 mod row;
 mod search_state;
 mod syntax;
 
+use self::buffer::Buffer;
 use self::cursor::Cursor;
-use self::key::{Key, ArrowKey};
+use self::encoding::Encoding;
+use self::file_format::{FileFormat, LineEnding};
+use self::grep::GrepMatch;
+use self::gutter::LineNumbers;
+use self::history::{Edit, EditKind, History};
+use self::key::{Key, ArrowKey, MouseEvent, MouseKind, PASTE_START, PASTE_END};
+use self::kill_ring::KillRing;
 use self::row::{Row, Highlight};
-use self::search_state::{Direction, Match, SearchState};
+use self::search_state::{Direction, Match, Pattern, SearchOptions, SearchState};
 use self::syntax::Syntax;
+use clipboard;
 use terminal;
 use util;
 
 use std::cmp;
-use std::io::{self, Read, BufRead, BufReader, Write};
-use std::fs::File;
+use std::env;
+use std::io::{self, Read, Write};
+use std::fs::{self, File};
+use std::mem;
+use std::path::Path;
 use std::process;
 use std::rc::Rc;
 use std::time::{Duration, SystemTime};
+use unicode_segmentation::UnicodeSegmentation;
 
-const KILO_VERSION: &'static str = "0.0.1";
+const KILO_VERSION: &str = "0.0.1";
 const KILO_QUIT_TIMES: u8 = 3;
+const DEFAULT_WINDOW_SIZE: (u16, u16) = (24, 80);
+const KILO_HISTORY_LEN: usize = 100;
+// Reads time out after a tenth of a second, so this is a second of nothing
+const KILO_PASTE_IDLE_READS: usize = 10;
 
 pub struct Editor {
     cursor: Cursor,
@@ -29,36 +52,81 @@ pub struct Editor {
     screen_rows: u16,
     screen_cols: u16,
     write_buffer: String,
-    rows: Vec<Row>,
+    buffer: Buffer,
+    // Whether each row starts inside a multiline comment, for as many rows as
+    // have been worked out since the last edit above them.
+    open_comments: Vec<bool>,
     dirty: bool,
     quit_times: u8,
     filename: String,
+    format: FileFormat,
     status_msg: String,
     status_time: SystemTime,
     syntax: Option<Rc<Syntax>>,
     search_state: SearchState,
+    search_options: SearchOptions,
+    search_history: Vec<String>,
+    // Which way the last search from the prompt went, for repeating it
+    search_direction: Direction,
+    // Set while showing search-in-files results instead of a file, along
+    // with the directory that was searched
+    results: Option<(String, Vec<GrepMatch>)>,
+    line_numbers: LineNumbers,
+    soft_wrap: bool,
+    // How many screen lines of the top row are scrolled off in soft-wrap mode
+    wrap_offset: usize,
+    history: History,
+    // The other end of the selection from the cursor, if there is one
+    mark: Option<Cursor>,
+    // Whether the mark was dropped by a shifted arrow, so plain movement ends it
+    shift_selecting: bool,
+    kill_ring: KillRing,
+    // Where the text from the last paste went, while the next key can still
+    // swap it for an older clipping
+    pasted: Option<(Cursor, Cursor)>,
 }
 
 impl Editor {
     pub fn new() -> Self {
-        // TODO: Default to 24x80 if None?
-        let (rows, cols) = terminal::get_window_size().unwrap();
-        Editor {
+        let mut editor = Editor {
             cursor: Cursor::new(),
             row_offset: 0,
             col_offset: 0,
-            screen_rows: rows - 2, // Leave space for status and message bars
-            screen_cols: cols,
+            screen_rows: 0,
+            screen_cols: 0,
             write_buffer: String::new(),
-            rows: Vec::new(),
+            buffer: Buffer::new(),
+            open_comments: Vec::new(),
             dirty: false,
             quit_times: KILO_QUIT_TIMES,
             filename: String::new(),
+            format: FileFormat::new(),
             status_msg: String::new(),
             status_time: SystemTime::now(),
             syntax: None,
             search_state: SearchState::new(),
-        }
+            search_options: SearchOptions::new(),
+            search_history: Vec::new(),
+            search_direction: Direction::Forward,
+            results: None,
+            line_numbers: LineNumbers::Off,
+            soft_wrap: false,
+            wrap_offset: 0,
+            history: History::new(),
+            mark: None,
+            shift_selecting: false,
+            kill_ring: KillRing::new(),
+            pasted: None,
+        };
+        editor.update_window_size();
+        editor
+    }
+
+    fn update_window_size(&mut self) {
+        let (rows, cols) = terminal::get_window_size().unwrap_or(DEFAULT_WINDOW_SIZE);
+        // Leave space for status and message bars
+        self.screen_rows = cmp::max(rows, 3) - 2;
+        self.screen_cols = cmp::max(cols, 1);
     }
 
     pub fn set_filename(&mut self, filename: String) {
@@ -68,120 +136,361 @@ impl Editor {
 
     pub fn set_syntax(&mut self, syntax: Option<Syntax>) {
         self.syntax = syntax.map(Rc::new);
-        for row in self.rows.iter_mut() { row.set_syntax(self.syntax.clone()) }
+        self.open_comments.clear();
     }
 
-    pub fn check_mlcomments(&mut self) {
-        let mut in_mlcomment = false;
-        for row in self.rows.iter_mut() {
-            let old_open_comment = row.open_comment;
-            row.open_comment = in_mlcomment;
-            if row.ends_mlcomment()   { in_mlcomment = false }
-            if row.starts_mlcomment() { in_mlcomment = true  }
-            if row.open_comment != old_open_comment { row.update_syntax() }
-        }
+    // Rows are rendered and highlighted on demand, so only the rows that are
+    // actually drawn pay for it.
+    fn row(&mut self, y: usize) -> Row {
+        let open_comment = self.open_comment(y);
+        let mut row = Row::new(self.buffer.line(y));
+        row.open_comment = open_comment;
+        row.set_syntax(self.syntax.clone());
+        row
     }
 
-    pub fn insert_char(&mut self, c: char) {
-        if self.cursor_past_end() {
-            let mut row = Row::new();
+    fn open_comment(&mut self, y: usize) -> bool {
+        let has_mlcomments = self.syntax.as_ref()
+            .is_some_and(|s| !s.multiline_comment_start.is_empty());
+        if !has_mlcomments { return false }
+        if self.open_comments.is_empty() { self.open_comments.push(false) }
+        while self.open_comments.len() <= y {
+            let prev = self.open_comments.len() - 1;
+            let mut row = Row::new(self.buffer.line(prev));
+            row.open_comment = self.open_comments[prev];
             row.set_syntax(self.syntax.clone());
-            self.rows.push(row);
-        }
-        let cursor_x = self.cursor.x;
-        {
-            let mut current_row = self.current_row_mut().unwrap();
-            current_row.insert_char(cursor_x, c);
+            self.open_comments.push(row.leaves_comment_open());
         }
-        self.cursor.x += 1;
-        self.dirty = true;
-        self.check_mlcomments();
+        self.open_comments[y]
+    }
+
+    pub fn insert_char(&mut self, c: char) {
+        let kind = if c.is_whitespace() { EditKind::Other } else { EditKind::Typing };
+        let (at, text) = self.insertion_point(c.to_string());
+        self.edit(Edit::Insert { at, text }, kind);
+    }
+
+    // Pasted text goes in as one edit, so it's undone in one go too.
+    pub fn insert_text(&mut self, text: String) {
+        if text.is_empty() { return }
+        let (at, text) = self.insertion_point(text);
+        self.edit(Edit::Insert { at, text }, EditKind::Other);
     }
 
     pub fn insert_newline(&mut self) {
-        if self.cursor.x == 0 {
-            let cursor_y = self.cursor.y;
-            self.insert_row(cursor_y, String::new());
-        } else {
-            let cursor_x = self.cursor.x;
-            let remainder = self.current_row_mut().unwrap().split_off(cursor_x);
-            let cursor_y = self.cursor.y;
-            self.insert_row(cursor_y + 1, remainder);
-        }
-        self.cursor.y += 1;
-        self.cursor.x = 0;
-        self.dirty = true;
-        self.check_mlcomments();
+        let (at, text) = self.insertion_point("\n".to_string());
+        self.edit(Edit::Insert { at, text }, EditKind::Other);
     }
 
     pub fn delete_char(&mut self) {
         if self.cursor_past_end() { return };
         if self.cursor.x == 0 && self.cursor.y == 0 { return };
-        let cursor_x = self.cursor.x;
-        if cursor_x == 0 {
-            let cursor_y = self.cursor.y;
-            self.cursor.x = self.rows[cursor_y - 1].contents.len();
-            // Is there a way to avoid this clone?
-            let s = self.current_row().unwrap().contents.clone();
-            self.rows[cursor_y - 1].append_string(&s);
-            self.delete_row(cursor_y);
-            self.cursor.y -= 1;
+        let end = self.cursor;
+        let start = if end.x == 0 {
+            Cursor { x: self.buffer.line(end.y - 1).len(), y: end.y - 1 }
+        } else {
+            let x = util::prev_grapheme_boundary(self.buffer.line(end.y), end.x);
+            Cursor { x, y: end.y }
+        };
+        let text = self.buffer.text_between(start, end);
+        self.edit(Edit::Delete { at: start, text }, EditKind::Deleting);
+    }
+
+    // Text typed while the cursor sits past the last row starts a new row,
+    // which is the same as inserting a newline at the end of the last row.
+    fn insertion_point(&self, text: String) -> (Cursor, String) {
+        if self.cursor_past_end() && !self.buffer.is_empty() {
+            let y = self.buffer.len() - 1;
+            let at = Cursor { x: self.buffer.line(y).len(), y };
+            (at, format!("\n{}", text))
         } else {
-            self.current_row_mut().unwrap().delete_char(cursor_x - 1);
-            self.cursor.x -= 1;
+            (self.cursor, text)
         }
+    }
+
+    fn edit(&mut self, edit: Edit, kind: EditKind) {
+        let cursor_before = self.cursor;
+        self.cursor = self.apply_edit(&edit);
+        self.history.record(edit, kind, cursor_before, self.cursor);
         self.dirty = true;
-        self.check_mlcomments();
     }
 
-    fn insert_row(&mut self, at: usize, s: String) {
-        if at <= self.rows.len() {
-            let mut row = Row::from_string(s);
-            row.set_syntax(self.syntax.clone());
-            self.rows.insert(at, row);
+    // Returns the position just past the inserted text, or the position the
+    // deleted text used to start at.
+    fn apply_edit(&mut self, edit: &Edit) -> Cursor {
+        let (at, cursor) = match *edit {
+            Edit::Insert { at, ref text } => (at, self.buffer.insert(at, text)),
+            Edit::SetLineEnding { to, .. } => {
+                self.format.line_ending = to;
+                return self.cursor;
+            },
+            Edit::Delete { at, ref text } => {
+                self.buffer.delete(at, Self::end_of_text(at, text));
+                (at, at)
+            },
         };
+        self.open_comments.truncate(at.y + 1);
+        // The mark could be anywhere after an edit
+        self.mark = None;
+        cursor
     }
 
-    fn delete_row(&mut self, at: usize) {
-        if at >= self.rows.len() { return }
-        self.rows.remove(at);
+    pub fn undo(&mut self) {
+        match self.history.undo() {
+            Some(group) => {
+                for edit in group.edits.iter().rev() {
+                    self.apply_edit(&edit.inverse());
+                }
+                self.cursor = group.cursor_before;
+                self.dirty = !self.history.is_saved();
+            },
+            None => self.set_status_message("Nothing to undo"),
+        }
     }
 
-    fn rows_to_string(&self) -> String {
-        self.rows.iter()
-            .map(|row| row.contents.clone())
-            .collect::<Vec<String>>()
-            .join("\n")
+    pub fn redo(&mut self) {
+        match self.history.redo() {
+            Some(group) => {
+                for edit in group.edits.iter() {
+                    self.apply_edit(edit);
+                }
+                self.cursor = group.cursor_after;
+                self.dirty = !self.history.is_saved();
+            },
+            None => self.set_status_message("Nothing to redo"),
+        }
+    }
+
+    fn toggle_mark(&mut self) {
+        if self.mark.take().is_some() {
+            self.set_status_message("Mark cleared");
+        } else {
+            self.mark = Some(self.cursor);
+            self.shift_selecting = false;
+            self.set_status_message("Mark set");
+        }
+    }
+
+    // The start and end of the text between the mark and the cursor, unless
+    // that's empty.
+    fn selection(&self) -> Option<(Cursor, Cursor)> {
+        let mark = self.clamp_to_text(self.mark?);
+        let cursor = self.clamp_to_text(self.cursor);
+        if (mark.y, mark.x) < (cursor.y, cursor.x) {
+            Some((mark, cursor))
+        } else if mark != cursor {
+            Some((cursor, mark))
+        } else {
+            None
+        }
+    }
+
+    // Past the last row is as good as the end of it.
+    fn clamp_to_text(&self, cursor: Cursor) -> Cursor {
+        if cursor.y < self.buffer.len() { return cursor }
+        match self.buffer.len() {
+            0 => Cursor::new(),
+            len => Cursor { x: self.buffer.line(len - 1).len(), y: len - 1 },
+        }
+    }
+
+    fn copy(&mut self) {
+        match self.selection() {
+            Some((start, end)) => {
+                let text = self.buffer.text_between(start, end);
+                self.export("Copied", &text);
+                self.kill_ring.push(text);
+                self.mark = None;
+            },
+            None => self.set_status_message("Nothing selected"),
+        }
+    }
+
+    fn cut(&mut self) {
+        match self.selection() {
+            Some((start, end)) => {
+                let text = self.buffer.text_between(start, end);
+                self.export("Cut", &text);
+                self.kill_ring.push(text.clone());
+                self.cursor = start;
+                self.edit(Edit::Delete { at: start, text }, EditKind::Other);
+            },
+            None => self.set_status_message("Nothing selected"),
+        }
+    }
+
+    // Puts what was cut or copied on the system clipboard as well, through
+    // the terminal, or a local clipboard tool if it's too big for that.
+    fn export(&mut self, verb: &str, text: &str) {
+        let mut msg = format!("{} {} bytes", verb, text.len());
+        if !terminal::set_clipboard(text) {
+            match clipboard::copy_with_tool(text) {
+                Some(tool) => msg.push_str(&format!(" (with {})", tool)),
+                None => msg.push_str(" (too big for the system clipboard)"),
+            }
+        }
+        self.set_status_message(&msg);
+    }
+
+    fn paste(&mut self) {
+        let text = match self.kill_ring.current() {
+            Some(text) => text.to_string(),
+            None => {
+                self.set_status_message("Nothing to paste");
+                return;
+            },
+        };
+        let len = text.len();
+        let (at, text) = self.insertion_point(text);
+        // Leave out the newline that starting a new row took
+        let start = if text.len() > len { Cursor { x: 0, y: at.y + 1 } } else { at };
+        self.edit(Edit::Insert { at, text }, EditKind::Other);
+        self.pasted = Some((start, self.cursor));
+    }
+
+    // Swaps what was just pasted for the clipping before it, as one edit.
+    fn cycle_paste(&mut self, pasted: Option<(Cursor, Cursor)>) {
+        let (start, end) = match pasted {
+            Some(range) => range,
+            None => {
+                self.set_status_message("Alt-y only works straight after pasting");
+                return;
+            },
+        };
+        let text = self.kill_ring.rotate().unwrap().to_string();
+        let old = self.buffer.text_between(start, end);
+        self.history.begin_group(self.cursor);
+        self.edit(Edit::Delete { at: start, text: old }, EditKind::Other);
+        self.edit(Edit::Insert { at: start, text }, EditKind::Other);
+        self.history.end_group();
+        self.pasted = Some((start, self.cursor));
+    }
+
+    fn end_of_text(at: Cursor, text: &str) -> Cursor {
+        match text.rfind('\n') {
+            Some(i) => Cursor {
+                x: text.len() - i - 1,
+                y: at.y + text.matches('\n').count(),
+            },
+            None => Cursor { x: at.x + text.len(), y: at.y },
+        }
     }
 
-    pub fn open_file(&mut self, filename: &str) {
-        let f = File::open(filename).unwrap(); // TODO: Handle error
-        let reader = BufReader::new(f);
-        self.rows = reader.lines()
-            .map(|line| line.unwrap_or(String::new()))
-            .map(Row::from_string).collect();
+    pub fn open_file(&mut self, filename: &str) -> io::Result<()> {
+        let mut f = File::open(filename)?;
+        let mut bytes = Vec::new();
+        f.read_to_end(&mut bytes)?;
+        let binary = encoding::looks_binary(&bytes);
+        let (format, contents) = FileFormat::decode(bytes);
+        self.format = format;
+        self.buffer = Buffer::from_string(contents, self.format.line_ending);
         self.set_filename(filename.to_string());
+        self.history = History::new();
+        self.dirty = false;
+        self.results = None;
+        self.mark = None;
+        if binary {
+            self.set_status_message("Warning: this looks like a binary file");
+        } else if self.format.encoding != Encoding::Utf8 {
+            let msg = format!("Not valid UTF-8; opened as {}", self.format.encoding.name());
+            self.set_status_message(&msg);
+        }
+        Ok(())
+    }
+
+    // Returns the number of bytes written, and whether the file was replaced
+    // in one go rather than overwritten.
+    pub fn save_file(&mut self) -> io::Result<(usize, bool)> {
+        let contents = self.format.encode(&self.buffer)?;
+        let atomic = util::write_atomically(&self.filename, &contents)?;
+        self.history.mark_saved();
         self.dirty = false;
-        self.check_mlcomments();
-        for row in self.rows.iter_mut() { row.update_syntax() }
+        Ok((contents.len(), atomic))
+    }
+
+    // Writes unsaved changes next to the file, or failing that to the temp
+    // directory. Returns where they went, if there was anything to write.
+    fn emergency_save(&self) -> io::Result<Option<String>> {
+        if !self.dirty { return Ok(None) }
+        let contents = self.format.encode(&self.buffer).or_else(|_| {
+            let format = FileFormat { encoding: Encoding::Utf8, ..self.format };
+            format.encode(&self.buffer)
+        })?;
+        let name = if self.filename.is_empty() {
+            format!("kilo-{}.recover", process::id())
+        } else {
+            format!("{}.recover", self.filename)
+        };
+        if fs::write(&name, &contents).is_ok() { return Ok(Some(name)) }
+        let file_name = Path::new(&name).file_name().unwrap();
+        let path = env::temp_dir().join(file_name).to_string_lossy().into_owned();
+        fs::write(&path, &contents)?;
+        Ok(Some(path))
+    }
+
+    // For when the editor can't carry on. The terminal has to be restored
+    // first so the message lands in the shell.
+    pub fn rescue(&self) {
+        let msg = match self.emergency_save() {
+            Ok(Some(path)) => format!("Unsaved changes written to {}", path),
+            Ok(None) => return,
+            Err(e) => format!("Couldn't save unsaved changes: {}", util::describe_io_error(&e)),
+        };
+        let _ = writeln!(io::stderr(), "{}", msg);
     }
 
-    pub fn save_file(&mut self) {
+    fn save(&mut self) {
         if self.filename.is_empty() {
-            match self.prompt(&|buf| format!("Save as: {}", buf), &|_, _, _|()) {
-                Some(name) => {
-                    self.set_filename(name);
+            match self.prompt(&|_, buf| format!("Save as: {}", buf), &|_, _, _|(), &mut Vec::new()) {
+                Some(ref name) if !name.is_empty() => {
+                    self.set_filename(name.clone());
                 },
-                None => {
+                _ => {
                     self.set_status_message("Save aborted");
                     return;
                 },
             }
         }
-        let mut f = File::create(&self.filename).unwrap(); // TODO: Handle error
-        let bytes = f.write(&self.rows_to_string().as_bytes()).unwrap();
-        self.set_status_message(&format!("{} bytes written to disk", bytes));
-        self.dirty = false;
+        match self.save_file() {
+            Ok((bytes, true)) => {
+                self.set_status_message(&format!("{} bytes written to disk", bytes));
+            },
+            Ok((bytes, false)) => {
+                // Couldn't make a new copy, so a crash part way through would
+                // have lost the file
+                let msg = format!("{} bytes written to disk in place, not atomically", bytes);
+                self.set_status_message(&msg);
+            },
+            Err(e) => {
+                let msg = format!("Can't save! {}", util::describe_io_error(&e));
+                self.set_status_message(&msg);
+            },
+        }
+    }
+
+    // Undone in one step together with any carriage returns it took out.
+    fn toggle_line_ending(&mut self) {
+        let from = self.format.line_ending;
+        let to = from.toggle();
+        let cursor = self.cursor;
+        self.history.begin_group(cursor);
+        self.edit(Edit::SetLineEnding { from, to }, EditKind::Other);
+        if to == LineEnding::CrLf {
+            // Stray carriage returns left over from a mixed file would
+            // otherwise end up doubled.
+            for y in 0..self.buffer.len() {
+                let len = self.buffer.line(y).len();
+                if self.buffer.line(y).ends_with('\r') {
+                    let at = Cursor { x: len - 1, y };
+                    self.edit(Edit::Delete { at, text: "\r".to_string() }, EditKind::Other);
+                }
+            }
+            self.cursor = cursor;
+            self.cursor.x = cmp::min(cursor.x, self.current_row_size().unwrap_or(0));
+        }
+        self.history.end_group();
+        let msg = format!("Line endings set to {}", self.format.line_ending.name());
+        self.set_status_message(&msg);
     }
 
     pub fn find(&mut self) {
@@ -190,83 +499,390 @@ impl Editor {
         let saved_row_offset = self.row_offset;
         self.search_state = SearchState::new();
 
-        let query = self.prompt(&|buf| format!("Search: {} (Use ESC/Arrows/Enter)", buf),
-                                &Self::find_callback);
-        if query.is_none() {
+        let mut history = mem::take(&mut self.search_history);
+        let query = self.prompt(&|editor, buf| editor.search_prompt("Search", buf),
+                                &Self::find_callback, &mut history);
+        self.search_history = history;
+        if query.is_some() {
+            self.search_direction = self.search_state.direction;
+        } else {
             self.cursor = saved_cursor;
             self.col_offset = saved_col_offset;
             self.row_offset = saved_row_offset;
         }
+        // Kept highlights stay until Escape or the next search
+        let pattern = self.search_state.pattern.take();
         self.search_state = SearchState::new();
+        if query.is_some() && self.search_options.keep_highlights {
+            self.search_state.pattern = pattern;
+        }
     }
 
-    fn find_callback(&mut self, query: &str, key: Key) {
-        let mut current = match self.search_state.last_match {
-            Some(Match { cursor, ref highlight }) => {
-                self.rows[cursor.y].highlight = highlight.clone();
-                cursor.y
+    fn search_prompt(&self, label: &str, query: &str) -> String {
+        let options = self.search_options.describe();
+        let label = if options.is_empty() {
+            label.to_string()
+        } else {
+            format!("{} [{}]", label, options)
+        };
+        let help = "ESC/Enter, Left/Right = prev/next, Up/Down = history, Alt-R/C/W/H = regex/case/word/keep";
+        match self.search_state.error {
+            Some(ref e) => format!("{}: {} ({})", label, query, e),
+            None if self.search_state.pattern.is_some() => {
+                let state = &self.search_state;
+                let wrapped = if state.wrapped { ", wrapped" } else { "" };
+                format!("{}: {} ({}/{} matches{}) ({})",
+                        label, query, state.match_index, state.match_count, wrapped, help)
+            },
+            None => format!("{}: {} ({})", label, query, help),
+        }
+    }
+
+    // Goes through the whole file from the top, asking about each match in
+    // turn. However many are replaced, they're undone together.
+    fn replace(&mut self) {
+        let saved_cursor = self.cursor;
+        let saved_col_offset = self.col_offset;
+        let saved_row_offset = self.row_offset;
+        self.search_state = SearchState::new();
+
+        let mut history = mem::take(&mut self.search_history);
+        let query = self.prompt(&|editor, buf| editor.search_prompt("Replace", buf),
+                                &Self::find_callback, &mut history);
+        self.search_history = history;
+        self.search_state = SearchState::new();
+        self.cursor = saved_cursor;
+        self.col_offset = saved_col_offset;
+        self.row_offset = saved_row_offset;
+        let query = match query {
+            Some(ref query) if !query.is_empty() => query.clone(),
+            _ => return,
+        };
+        let pattern = match self.search_options.compile(&query) {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                self.set_status_message(&format!("Invalid pattern: {}", e));
+                return;
             },
-            None => 0,
+        };
+        let replacement = match self.prompt(&|_, buf| format!("Replace with: {}", buf),
+                                            &|_, _, _|(), &mut Vec::new()) {
+            Some(replacement) => replacement,
+            None => return,
         };
 
-        match key {
-            Key::Control('M') | Key::Escape => return,
-            Key::Arrow(ak) => {
-                match ak {
-                    ArrowKey::Left | ArrowKey::Up => {
-                        current -= 1;
-                        self.search_state.direction = Direction::Backward;
+        let mut from = Cursor::new();
+        let mut found = false;
+        let mut replace_all = false;
+        let mut count = 0;
+        self.history.begin_group(saved_cursor);
+        self.search_state.pattern = Some(pattern.clone());
+        while let Some(m) = self.find_from(&pattern, from) {
+            found = true;
+            self.cursor = m.cursor;
+            self.search_state.last_match = Some(m);
+            if !replace_all {
+                match self.confirm_replace() {
+                    'y' => (),
+                    'n' => {
+                        from = self.step_past(m);
+                        continue;
                     },
-                    ArrowKey::Right | ArrowKey::Down => {
-                        current += 1;
-                        self.search_state.direction = Direction::Forward;
-                    },
-                };
-            },
-            _ => (),
+                    'a' => replace_all = true,
+                    _ => break,
+                }
+            }
+            let text = if self.search_options.regex {
+                let line = self.buffer.line(m.cursor.y);
+                let captures = pattern.regex.captures_at(line, m.cursor.x).unwrap();
+                let mut text = String::new();
+                captures.expand(&replacement, &mut text);
+                text
+            } else {
+                replacement.clone()
+            };
+            if m.len > 0 {
+                let end = Cursor { x: m.cursor.x + m.len, y: m.cursor.y };
+                let old = self.buffer.text_between(m.cursor, end);
+                self.edit(Edit::Delete { at: m.cursor, text: old }, EditKind::Other);
+            }
+            if !text.is_empty() {
+                self.edit(Edit::Insert { at: m.cursor, text }, EditKind::Other);
+            }
+            // An empty match has to be stepped over, or it would be found
+            // again straight after the replacement.
+            from = if m.len == 0 {
+                self.step_past(Match { cursor: self.cursor, len: 0 })
+            } else {
+                self.cursor
+            };
+            count += 1;
         }
+        self.history.end_group();
+        self.search_state = SearchState::new();
 
-        if query.is_empty() { return }
+        if found {
+            let plural = if count == 1 { "" } else { "s" };
+            self.set_status_message(&format!("{} replacement{} made", count, plural));
+        } else {
+            self.set_status_message(&format!("No matches for {}", query));
+        }
+    }
 
-        let num_rows = self.rows.len();
+    fn confirm_replace(&mut self) -> char {
+        self.set_status_message("Replace this match? (y)es (n)o (a)ll (q)uit");
+        loop {
+            self.refresh_screen();
+            match Self::read_key() {
+                Some(Key::Character(c)) if "ynaq".contains(c) => return c,
+                Some(Key::Escape) => return 'q',
+                _ => (),
+            }
+        }
+    }
 
-        let res = match self.search_state.direction {
-            Direction::Forward => {
-                let iter = self.rows.iter().enumerate()
-                    .cycle().skip(current).take(num_rows);
-                Self::find_in_rows(iter, query)
+    // Lists matches from every file under the current file's directory in a
+    // read-only buffer. Enter on one of them opens that file there.
+    fn search_in_files(&mut self) {
+        if self.dirty {
+            self.set_status_message("Save your changes before searching in files");
+            return;
+        }
+        self.search_state = SearchState::new();
+        let mut history = mem::take(&mut self.search_history);
+        let query = self.prompt(&|editor, buf| editor.search_prompt("Search in files", buf),
+                                &Self::grep_callback, &mut history);
+        self.search_history = history;
+        self.search_state = SearchState::new();
+        let query = match query {
+            Some(ref query) if !query.is_empty() => query.clone(),
+            _ => return,
+        };
+        let pattern = match self.search_options.compile(&query) {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                self.set_status_message(&format!("Invalid pattern: {}", e));
+                return;
             },
-            Direction::Backward => {
-                let iter = self.rows.iter().enumerate().rev()
-                    .cycle().skip(num_rows - current - 1).take(num_rows);
-                Self::find_in_rows(iter, query)
+        };
+        // Searching again from the results goes back over the same files
+        let dir = match (&self.results, Path::new(&self.filename).parent()) {
+            (&Some((ref dir, _)), _) => dir.clone(),
+            (_, Some(dir)) if !dir.as_os_str().is_empty() => dir.to_string_lossy().into_owned(),
+            _ => ".".to_string(),
+        };
+
+        let (matches, truncated) = grep::grep(&dir, &pattern);
+        let text = matches.iter()
+            .map(|m| format!("{}:{}:{}", m.path, m.cursor.y + 1, m.line))
+            .collect::<Vec<String>>()
+            .join("\n");
+        self.buffer = Buffer::from_string(text, LineEnding::Lf);
+        self.format = FileFormat::new();
+        self.history = History::new();
+        self.filename = String::new();
+        self.set_syntax(None);
+        self.cursor = Cursor::new();
+        self.mark = None;
+        self.row_offset = 0;
+        self.col_offset = 0;
+        let mut msg = format!("{} matches for {} in {}", matches.len(), query, dir);
+        if truncated { msg.push_str(" (stopped there)") }
+        self.set_status_message(&msg);
+        self.results = Some((dir, matches));
+    }
+
+    fn grep_callback(&mut self, query: &str, key: Key) {
+        self.toggle_search_option(&key);
+        self.search_state.error = self.search_options.compile(query).err();
+    }
+
+    fn open_result(&mut self) {
+        let (path, cursor) = match self.results.as_ref().and_then(|r| r.1.get(self.cursor.y)) {
+            Some(m) => (m.path.clone(), m.cursor),
+            None => return,
+        };
+        if let Err(e) = self.open_file(&path) {
+            let msg = format!("Can't open {}: {}", path, util::describe_io_error(&e));
+            self.set_status_message(&msg);
+            return;
+        }
+        // The file may have changed since it was searched
+        self.cursor.y = cmp::min(cursor.y, self.buffer.len());
+        self.cursor.x = if self.cursor_past_end() {
+            0
+        } else {
+            let line = self.buffer.line(self.cursor.y);
+            util::floor_grapheme_boundary(line, cmp::min(cursor.x, line.len()))
+        };
+        self.row_offset = 0;
+        self.col_offset = 0;
+        self.set_status_message("");
+    }
+
+    // Repeats the last search from the cursor without opening the prompt.
+    // Going back means against the way that search went.
+    fn find_again(&mut self, back: bool) {
+        let query = match self.search_history.last() {
+            Some(query) => query.clone(),
+            None => {
+                self.set_status_message("No previous search");
+                return;
             },
         };
-        match res {
-            Some(cursor) => {
-                self.search_state.last_match = Some(Match {
-                    cursor: cursor,
-                    highlight: self.rows[cursor.y].highlight.clone()
-                });
-                self.cursor = cursor;
-                self.row_offset = self.rows.len();
-
-                for i in cursor.x..cursor.x + query.len() {
-                    self.rows[cursor.y].highlight[i] = Highlight::Match;
+        let pattern = match self.search_options.compile(&query) {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                self.set_status_message(&format!("Invalid pattern: {}", e));
+                return;
+            },
+        };
+        let direction = if back {
+            self.search_direction.reverse()
+        } else {
+            self.search_direction
+        };
+        match self.find_next(&pattern, self.cursor, direction, true) {
+            Some((m, wrapped)) => {
+                self.cursor = m.cursor;
+                if wrapped && direction == Direction::Forward {
+                    self.set_status_message("Passed the end of the file, continuing from the top");
+                } else if wrapped {
+                    self.set_status_message("Passed the start of the file, continuing from the bottom");
                 }
             },
+            None => self.set_status_message(&format!("No matches for {}", query)),
+        }
+    }
+
+    // The nearest match to at going in the given direction, wrapping around
+    // at either end of the file, and whether it had to wrap. With skip, a
+    // match starting right at at doesn't count.
+    fn find_next(&self, pattern: &Pattern, at: Cursor, direction: Direction,
+                 skip: bool) -> Option<(Match, bool)> {
+        let past = self.step_past(Match { cursor: at, len: 0 });
+        match direction {
+            Direction::Forward => {
+                let from = if skip { past } else { at };
+                self.find_from(pattern, from).map(|m| (m, false))
+                    .or_else(|| self.find_from(pattern, Cursor::new()).map(|m| (m, true)))
+            },
+            Direction::Backward => {
+                let before = if skip { at } else { past };
+                let end = Cursor { x: 0, y: self.buffer.len() };
+                self.find_before(pattern, before).map(|m| (m, false))
+                    .or_else(|| self.find_before(pattern, end).map(|m| (m, true)))
+            },
+        }
+    }
+
+    // The first match at or after from, without wrapping around.
+    fn find_from(&self, pattern: &Pattern, from: Cursor) -> Option<Match> {
+        (from.y..self.buffer.len()).filter_map(|y| {
+            let x = if y == from.y { from.x } else { 0 };
+            pattern.find_at(self.buffer.line(y), x).map(|(start, end)| Match {
+                cursor: Cursor { x: start, y },
+                len: end - start,
+            })
+        }).next()
+    }
+
+    // The last match that starts before at, without wrapping around.
+    fn find_before(&self, pattern: &Pattern, at: Cursor) -> Option<Match> {
+        (0..cmp::min(at.y + 1, self.buffer.len())).rev().filter_map(|y| {
+            pattern.matches(self.buffer.line(y))
+                .take_while(|&(start, _)| y < at.y || start < at.x)
+                .last()
+                .map(|(start, end)| Match { cursor: Cursor { x: start, y }, len: end - start })
+        }).next()
+    }
+
+    // Where to carry on looking after m, going on to the next line after an
+    // empty match at the end of one.
+    fn step_past(&self, m: Match) -> Cursor {
+        if m.cursor.y >= self.buffer.len() { return m.cursor }
+        let line = self.buffer.line(m.cursor.y);
+        let end = m.cursor.x + m.len;
+        if m.len > 0 {
+            Cursor { x: end, y: m.cursor.y }
+        } else if end < line.len() {
+            Cursor { x: util::next_grapheme_boundary(line, end), y: m.cursor.y }
+        } else {
+            Cursor { x: 0, y: m.cursor.y + 1 }
+        }
+    }
+
+    // Typing searches again from the current match, so it stays put for as
+    // long as it still matches; Left and Right move on to the one before or
+    // after it.
+    fn find_callback(&mut self, query: &str, key: Key) {
+        if self.buffer.is_empty() { return }
+        let last_match = self.search_state.last_match.take();
+        let mut skip = false;
+
+        match key {
+            Key::Control('M') | Key::Escape => return,
+            // Up and Down go through the history instead
+            Key::Arrow(ArrowKey::Left) => {
+                self.search_state.direction = Direction::Backward;
+                skip = true;
+            },
+            Key::Arrow(ArrowKey::Right) => {
+                self.search_state.direction = Direction::Forward;
+                skip = true;
+            },
+            _ => self.toggle_search_option(&key),
+        }
+
+        self.search_state.error = None;
+        self.search_state.pattern = None;
+        self.search_state.wrapped = false;
+        if query.is_empty() { return }
+        let pattern = match self.search_options.compile(query) {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                self.search_state.error = Some(e);
+                self.search_state.last_match = last_match;
+                return
+            },
+        };
+
+        let at = last_match.map_or(self.cursor, |m| m.cursor);
+        let direction = self.search_state.direction;
+        if let Some((m, wrapped)) = self.find_next(&pattern, at, direction, skip) {
+            self.search_state.last_match = Some(m);
+            self.search_state.wrapped = wrapped;
+            self.cursor = m.cursor;
+            self.row_offset = self.buffer.len();
+        }
+        self.count_matches(&pattern);
+        self.search_state.pattern = Some(pattern);
+    }
+
+    fn toggle_search_option(&mut self, key: &Key) {
+        let options = &mut self.search_options;
+        match *key {
+            Key::Alt('r') => options.regex = !options.regex,
+            Key::Alt('c') => options.case = options.case.next(),
+            Key::Alt('w') => options.whole_word = !options.whole_word,
+            Key::Alt('h') => options.keep_highlights = !options.keep_highlights,
             _ => (),
         }
     }
 
-    fn find_in_rows<'a, T: Iterator<Item=(usize, &'a Row)>>(iter: T, query: &str) -> Option<Cursor> {
-        let res = iter.map(|(y, ref row)| {
-            let x = row.render.find(&query)
-                .map(|x| row.raw_cursor_x(x));
-            (x, y)
-        })
-        .find(|&(option_x, _)| option_x.is_some());
-        res.map(|(option_x, y)| Cursor { x: option_x.unwrap(), y: y })
+    // How many matches there are in all, and which one is the current one.
+    fn count_matches(&mut self, pattern: &Pattern) {
+        let current = self.search_state.last_match.map(|m| m.cursor);
+        let mut count = 0;
+        let mut index = 0;
+        for (y, line) in self.buffer.lines().enumerate() {
+            for (x, _) in pattern.matches(line) {
+                count += 1;
+                if current == Some(Cursor { x, y }) { index = count }
+            }
+        }
+        self.search_state.match_count = count;
+        self.search_state.match_index = index;
     }
 
     fn rendered_cursor_x(&self) -> usize {
@@ -275,14 +891,30 @@ impl Editor {
     }
 
     pub fn refresh_screen(&mut self) {
+        if let Some(signal) = terminal::fatal_signal() {
+            terminal::restore();
+            self.rescue();
+            process::exit(128 + signal);
+        }
+        let resumed = terminal::resumed();
+        if terminal::window_resized() || resumed {
+            // scroll() brings the offsets back in line with the new size
+            self.update_window_size();
+            self.write_buffer.push_str("\x1b[2J");
+        }
         self.scroll();
+        let lines = self.screen_lines();
         self.write_buffer.push_str("\x1b[?25l");
         self.write_buffer.push_str("\x1b[H");
-        self.draw_rows();
+        self.draw_rows(&lines);
         self.draw_status_bar();
         self.draw_message_bar();
-        let cursor_y = self.cursor.y - self.row_offset + 1;
-        let cursor_x = self.rendered_cursor_x() - self.col_offset + 1;
+        let rx = self.rendered_cursor_x();
+        let (cursor_y, cursor_x) = match lines.iter().rposition(|l| l.0 == self.cursor.y && l.2 <= rx) {
+            Some(i) => (i + 1, self.gutter_width() + rx - lines[i].2 + 1),
+            // Past the last row
+            None => (lines.len() + 1, self.gutter_width() + 1),
+        };
         let set_cursor = format!("\x1b[{};{}H", cursor_y, cursor_x);
         self.write_buffer.push_str(&set_cursor);
         self.write_buffer.push_str("\x1b[?25h");
@@ -292,7 +924,10 @@ impl Editor {
     }
 
     fn scroll(&mut self) {
+        if self.soft_wrap { return self.scroll_wrapped() }
         let rx = self.rendered_cursor_x();
+        // Both columns of a wide character under the cursor need to be shown
+        let rx_end = rx + self.current_row().map_or(1, |row| row.cursor_width(self.cursor.x));
         if self.cursor.y < self.row_offset {
             self.row_offset = self.cursor.y;
         } else if self.cursor.y >= self.row_offset + (self.screen_rows as usize) {
@@ -300,61 +935,150 @@ impl Editor {
         }
         if rx < self.col_offset {
             self.col_offset = rx;
-        } else if rx >= self.col_offset + (self.screen_cols as usize) {
-            self.col_offset = rx - (self.screen_cols as usize) + 1;
+        } else if rx_end > self.col_offset + self.text_cols() {
+            self.col_offset = rx_end - self.text_cols();
+        }
+    }
+
+    // Like scroll, but counting screen lines rather than rows, since one row
+    // can take up several.
+    fn scroll_wrapped(&mut self) {
+        self.col_offset = 0;
+        let screen_rows = self.screen_rows as usize;
+        let (seg, _) = self.cursor_segment();
+        if (self.cursor.y, seg) < (self.row_offset, self.wrap_offset) {
+            self.row_offset = self.cursor.y;
+            self.wrap_offset = seg;
+            return;
+        }
+        // Every row takes at least one line, so rows further up than this
+        // can't be on screen with the cursor
+        if self.cursor.y >= self.row_offset + screen_rows {
+            self.row_offset = self.cursor.y + 1 - screen_rows;
+            self.wrap_offset = 0;
+        }
+        let heights = (self.row_offset..self.cursor.y)
+            .map(|y| self.wrap_points(y).len())
+            .collect::<Vec<usize>>();
+        if let Some(&height) = heights.first() {
+            self.wrap_offset = cmp::min(self.wrap_offset, height - 1);
+        }
+        let mut lines = heights.iter().sum::<usize>() + seg + 1 - self.wrap_offset;
+        let mut i = 0;
+        while lines > screen_rows {
+            // Once the cursor's own row is at the top, only the lines above
+            // the cursor's can go
+            let height = heights.get(i).cloned().unwrap_or(seg + 1);
+            if self.wrap_offset + 1 < height {
+                self.wrap_offset += 1;
+            } else {
+                self.row_offset += 1;
+                self.wrap_offset = 0;
+                i += 1;
+            }
+            lines -= 1;
+        }
+    }
+
+    fn wrap_points(&self, y: usize) -> Vec<usize> {
+        Row::new(self.buffer.line(y)).wrap_points(self.text_cols())
+    }
+
+    // Which of the current row's screen lines the cursor is on in soft-wrap
+    // mode, along with where they all start.
+    fn cursor_segment(&self) -> (usize, Vec<usize>) {
+        if self.cursor_past_end() { return (0, vec![0]) }
+        let starts = self.wrap_points(self.cursor.y);
+        let rx = self.rendered_cursor_x();
+        let seg = starts.iter().rposition(|&start| start <= rx).unwrap_or(0);
+        (seg, starts)
+    }
+
+    // What goes on each line of the screen: the row, which of its wrapped
+    // lines it is, and the range of render columns to show.
+    fn screen_lines(&self) -> Vec<(usize, usize, usize, usize)> {
+        let screen_rows = self.screen_rows as usize;
+        let width = self.text_cols();
+        let mut lines = Vec::new();
+        if !self.soft_wrap {
+            let end = cmp::min(self.buffer.len(), self.row_offset + screen_rows);
+            for y in self.row_offset..end {
+                lines.push((y, 0, self.col_offset, self.col_offset + width));
+            }
+            return lines;
         }
+        let mut skip = self.wrap_offset;
+        for y in self.row_offset..self.buffer.len() {
+            let starts = self.wrap_points(y);
+            for (seg, &start) in starts.iter().enumerate().skip(skip) {
+                if lines.len() == screen_rows { return lines }
+                let end = starts.get(seg + 1).cloned().unwrap_or(start + width);
+                lines.push((y, seg, start, end));
+            }
+            skip = 0;
+        }
+        lines
+    }
+
+    fn toggle_soft_wrap(&mut self) {
+        self.soft_wrap = !self.soft_wrap;
+        self.wrap_offset = 0;
+        self.col_offset = 0;
+        let msg = format!("Soft wrap {}", if self.soft_wrap { "on" } else { "off" });
+        self.set_status_message(&msg);
+    }
+
+    fn gutter_width(&self) -> usize {
+        self.line_numbers.width(self.buffer.len())
+    }
+
+    // Columns left over for text once the gutter is drawn.
+    fn text_cols(&self) -> usize {
+        cmp::max(1, (self.screen_cols as usize).saturating_sub(self.gutter_width()))
     }
 
-    fn draw_rows(&mut self) {
+    fn toggle_line_numbers(&mut self) {
+        self.line_numbers = self.line_numbers.next();
+        let msg = format!("Line numbers: {}", self.line_numbers.name());
+        self.set_status_message(&msg);
+    }
+
+    fn draw_rows(&mut self, lines: &[(usize, usize, usize, usize)]) {
+        let mut cached: Option<(usize, Row)> = None;
         for i in 0..self.screen_rows as usize {
-            let file_row = i + self.row_offset;
-            if file_row >= self.rows.len() {
-                if self.rows.is_empty() && i == (self.screen_rows as usize) / 3 {
-                    let mut welcome = format!("Kilo editor -- version {}", KILO_VERSION);
-                    util::safe_truncate(&mut welcome, self.screen_cols as usize);
-
-                    let padding = (self.screen_cols as usize - welcome.len()) / 2;
-                    if padding > 0 {
-                        self.write_buffer.push_str("~");
-                        let spaces = " ".repeat(padding - 1);
-                        self.write_buffer.push_str(&spaces);
-                    }
+            match lines.get(i) {
+                None => {
+                    if self.buffer.is_empty() && i == (self.screen_rows as usize) / 3 {
+                        let mut welcome = format!("Kilo editor -- version {}", KILO_VERSION);
+                        util::truncate_to_width(&mut welcome, self.screen_cols as usize);
 
-                    self.write_buffer.push_str(&welcome);
-                } else {
-                    self.write_buffer.push_str("~");
-                }
-            } else {
-                let ref row = self.rows[file_row];
-                let mut current_color = 0;
-                let render = row.render.char_indices()
-                    .skip(self.col_offset).take(self.screen_cols as usize)
-                    .map(|(i, c)| {
-                        if c.is_control() {
-                            let sym = if c as u8 <= 26 {
-                                ('@' as u8 + c as u8) as char
-                            } else {
-                                '?'
-                            };
-                            let reset = if current_color > 0 {
-                                format!("\x1b[{}m", current_color)
-                            } else {
-                                String::new()
-                            };
-                            format!("\x1b[7m{}\x1b[m{}", sym, reset)
-                        } else {
-                            let color = row.highlight[i].to_color();
-                            if color != current_color {
-                                current_color = color;
-                                format!("\x1b[{}m{}", color, c)
-                            } else {
-                                c.to_string()
-                            }
+                        let padding = (self.screen_cols as usize - util::display_width(&welcome)) / 2;
+                        if padding > 0 {
+                            self.write_buffer.push('~');
+                            let spaces = " ".repeat(padding - 1);
+                            self.write_buffer.push_str(&spaces);
                         }
-                    })
-                    .collect::<String>();
-                self.write_buffer.push_str(&render);
-                self.write_buffer.push_str("\x1b[39m");
+
+                        self.write_buffer.push_str(&welcome);
+                    } else {
+                        self.write_buffer.push('~');
+                    }
+                },
+                Some(&(file_row, seg, start, end)) => {
+                    let gutter = self.gutter_width();
+                    if gutter > 0 && seg > 0 {
+                        self.write_buffer.push_str(&" ".repeat(gutter));
+                    } else if gutter > 0 {
+                        let number = self.line_numbers.number(file_row, self.cursor.y);
+                        self.write_buffer.push_str(&format!("\x1b[90m{:>1$} \x1b[39m", number, gutter - 1));
+                    }
+                    // A wrapped row is drawn a line at a time
+                    if cached.as_ref().is_none_or(|&(y, _)| y != file_row) {
+                        cached = Some((file_row, self.highlighted_row(file_row)));
+                    }
+                    let row = &cached.as_ref().unwrap().1;
+                    self.draw_row(row, start, end);
+                },
             }
 
             self.write_buffer.push_str("\x1b[K");
@@ -362,28 +1086,109 @@ impl Editor {
         }
     }
 
+    fn highlighted_row(&mut self, y: usize) -> Row {
+        let mut row = self.row(y);
+        let indices = row.render_indices();
+        if let Some(ref pattern) = self.search_state.pattern {
+            let matches = pattern.matches(&row.contents).collect::<Vec<_>>();
+            for (start, end) in matches {
+                row.highlight_range(&indices, start, end, Highlight::Match);
+            }
+        }
+        if let Some(Match { cursor, len }) = self.search_state.last_match {
+            if cursor.y == y {
+                row.highlight_range(&indices, cursor.x, cursor.x + len, Highlight::Match);
+            }
+        }
+        if let Some((start, end)) = self.selection() {
+            if start.y <= y && y <= end.y {
+                let from = if y == start.y { start.x } else { 0 };
+                let to = if y == end.y { end.x } else { row.contents.len() };
+                row.highlight_range(&indices, from, to, Highlight::Selection);
+            }
+        }
+        row
+    }
+
+    // Draws the part of row between render columns from and to.
+    fn draw_row(&mut self, row: &Row, from: usize, to: usize) {
+        let mut current_color = 0;
+        let mut rx = 0;
+        let mut i = 0;
+        for g in row.render.graphemes(true) {
+            let hl = row.highlight[i];
+            i += g.chars().count();
+            let start = rx;
+            rx += util::grapheme_width(g);
+            if start < from {
+                // Pad out what's left of a wide character that has been
+                // partly scrolled off the left edge
+                if rx > from {
+                    self.write_buffer.push_str(&" ".repeat(rx - from));
+                }
+                continue;
+            }
+            if rx > to { break }
+
+            let c = g.chars().next().unwrap();
+            if c.is_control() {
+                let sym = if (c as u32) <= 26 {
+                    (b'@' + c as u8) as char
+                } else {
+                    '?'
+                };
+                let reset = if current_color > 0 {
+                    format!("\x1b[{}m", current_color)
+                } else {
+                    String::new()
+                };
+                self.write_buffer.push_str(&format!("\x1b[7m{}\x1b[m{}", sym, reset));
+            } else {
+                let color = hl.to_color();
+                if color != current_color {
+                    // Another colour doesn't turn reverse video off
+                    if current_color == 7 { self.write_buffer.push_str("\x1b[27m") }
+                    current_color = color;
+                    self.write_buffer.push_str(&format!("\x1b[{}m", color));
+                }
+                self.write_buffer.push_str(g);
+            }
+        }
+        self.write_buffer.push_str("\x1b[27;39m");
+    }
+
     fn draw_status_bar(&mut self) {
         self.write_buffer.push_str("\x1b[7m");
 
         let mut filename = self.filename.clone();
-        if filename.is_empty() {
+        if self.results.is_some() {
+            filename.push_str("[Search results]")
+        } else if filename.is_empty() {
             filename.push_str("[No Name]")
         } else {
-            util::safe_truncate(&mut filename, 20);
+            util::truncate_to_width(&mut filename, 20);
         }
-        let modified = if self.dirty { "(modified)" } else { "" };
-        let mut status = format!("{} - {} lines {}", filename, self.rows.len(), modified);
+        let modified = if self.results.is_some() {
+            "(read-only)"
+        } else if self.dirty {
+            "(modified)"
+        } else {
+            ""
+        };
+        let mut status = format!("{} - {} lines {}", filename, self.buffer.len(), modified);
         let syntax = match self.syntax {
             Some(ref s) => s.filetype,
             None => "no ft",
         };
-        let rstatus = format!("{} | {}/{}", syntax, self.cursor.y + 1, self.rows.len());
-        if self.screen_cols as usize > status.len() + rstatus.len() {
-            let padding = self.screen_cols as usize - status.len() - rstatus.len();
+        let rstatus = format!("{} | {} | {}/{}", syntax, self.format.describe(),
+                              self.cursor.y + 1, self.buffer.len());
+        let width = util::display_width(&status) + util::display_width(&rstatus);
+        if self.screen_cols as usize > width {
+            let padding = self.screen_cols as usize - width;
             status.push_str(&" ".repeat(padding));
         }
         status.push_str(&rstatus);
-        util::safe_truncate(&mut status, self.screen_cols as usize);
+        util::truncate_to_width(&mut status, self.screen_cols as usize);
         self.write_buffer.push_str(&status);
 
         self.write_buffer.push_str("\x1b[m");
@@ -398,7 +1203,7 @@ impl Editor {
     fn draw_message_bar(&mut self) {
         self.write_buffer.push_str("\x1b[K");
         let mut message = self.status_msg.clone();
-        util::safe_truncate(&mut message, self.screen_cols as usize);
+        util::truncate_to_width(&mut message, self.screen_cols as usize);
         if self.status_time.elapsed().unwrap() < Duration::from_secs(5) {
             self.write_buffer.push_str(&message);
         }
@@ -406,56 +1211,114 @@ impl Editor {
 
     fn read_key() -> Option<Key> {
         let stdin = io::stdin();
-        let c = stdin.lock().bytes().next().and_then(|res| res.ok());
-        if c.is_none() { return None }
-        if c != Some(b'\x1b') { return Key::from_byte(c.unwrap()) }
-        let mut seq: Vec<u8> = stdin.lock().bytes().take(2).map(|res| res.ok().unwrap()).collect();
-        if seq[0] == b'[' && seq[1] >= b'0' && seq[1] <= b'9' {
-            seq.push(stdin.lock().bytes().next().and_then(|res| res.ok()).unwrap());
+        let mut bytes = stdin.lock().bytes().filter_map(|res| res.ok());
+        let c = bytes.next()?;
+        if c != b'\x1b' { return Self::read_char(c, &mut bytes) }
+        // ESC on its own is the escape key; anything straight after it is
+        // either an escape sequence or a key pressed with Alt.
+        match bytes.next() {
+            None => Some(Key::Escape),
+            Some(b) if b == b'[' || b == b'O' => {
+                let mut seq = vec![b];
+                // Parameters carry on until a final byte in @..~
+                for b in bytes.by_ref() {
+                    seq.push(b);
+                    if (0x40..=0x7e).contains(&b) { break }
+                }
+                if seq == PASTE_START { return Some(Self::read_paste(&mut bytes)) }
+                Some(Key::from_escape_sequence(&seq))
+            },
+            // Alt with a non-ASCII character sends all of its bytes
+            Some(b) => Some(Key::with_alt(Self::read_char(b, &mut bytes))),
+        }
+    }
+
+    // The key starting with byte c, reading the rest of it if it's the
+    // start of a UTF-8 sequence.
+    fn read_char<I: Iterator<Item=u8>>(c: u8, bytes: &mut I) -> Option<Key> {
+        let len = Key::utf8_len(c);
+        if len == 1 { return Key::from_byte(c) }
+        let mut seq = vec![c];
+        seq.extend(bytes.take(len - 1));
+        Key::from_utf8(&seq)
+    }
+
+    // Everything up to the end of a paste, however many reads it takes to
+    // come in. If the end never turns up, or we're asked to quit, whatever
+    // did arrive goes in.
+    fn read_paste<I: Iterator<Item=u8>>(bytes: &mut I) -> Key {
+        let mut pasted = Vec::new();
+        let mut idle = 0;
+        while !pasted.ends_with(PASTE_END) {
+            match bytes.next() {
+                Some(b) => {
+                    pasted.push(b);
+                    idle = 0;
+                },
+                None if idle < KILO_PASTE_IDLE_READS && terminal::fatal_signal().is_none() => idle += 1,
+                None => return Key::from_paste(&pasted),
+            }
         }
-        Some(Key::from_escape_sequence(&seq))
+        pasted.truncate(pasted.len() - PASTE_END.len());
+        Key::from_paste(&pasted)
     }
 
     fn cursor_past_end(&self) -> bool {
-        self.cursor.y >= self.rows.len()
+        self.cursor.y >= self.buffer.len()
     }
 
-    fn current_row(&self) -> Option<&Row> {
+    fn current_row(&self) -> Option<Row> {
         if self.cursor_past_end() {
             None
         } else {
-            Some(&self.rows[self.cursor.y])
+            Some(Row::new(self.buffer.line(self.cursor.y)))
         }
     }
 
-    fn current_row_mut(&mut self) -> Option<&mut Row> {
+    fn current_row_size(&self) -> Option<usize> {
         if self.cursor_past_end() {
             None
         } else {
-            Some(&mut self.rows[self.cursor.y])
+            Some(self.buffer.line(self.cursor.y).len())
         }
     }
 
-    fn current_row_size(&self) -> Option<usize> {
-        self.current_row().map(|row| row.contents.len())
-    }
-
-    fn prompt(&mut self, prompt: (&Fn(&str) -> String),
-              callback: (&Fn(&mut Self, &str, Key))) -> Option<String> {
+    // Up and Down go back and forth through history, which gets whatever is
+    // entered added to the end of it.
+    fn prompt(&mut self, prompt: &dyn Fn(&Self, &str) -> String,
+              callback: &dyn Fn(&mut Self, &str, Key),
+              history: &mut Vec<String>) -> Option<String> {
         let mut buffer = String::new();
+        // What was typed before going back through history
+        let mut draft = String::new();
+        let mut recalled = history.len();
         loop {
-            self.set_status_message(&prompt(&buffer));
+            let msg = prompt(self, &buffer);
+            self.set_status_message(&msg);
             self.refresh_screen();
             let key = Self::read_key();
             if key.is_none() { continue }
             let key = key.unwrap();
             match key {
                 Key::Character(c) => buffer.push(c),
+                // A prompt only takes one line
+                Key::Paste(ref text) => buffer.extend(text.chars().filter(|c| !c.is_control())),
+                Key::Arrow(ArrowKey::Up) if recalled > 0 => {
+                    if recalled == history.len() { draft = buffer.clone() }
+                    recalled -= 1;
+                    buffer = history[recalled].clone();
+                },
+                Key::Arrow(ArrowKey::Down) if recalled < history.len() => {
+                    recalled += 1;
+                    buffer = history.get(recalled).cloned().unwrap_or_else(|| draft.clone());
+                },
                 Key::Control('M') => {
-                    if buffer.len() > 0 {
-                        callback(self, &buffer, key);
-                        break
+                    if !buffer.is_empty() && history.last() != Some(&buffer) {
+                        history.push(buffer.clone());
+                        if history.len() > KILO_HISTORY_LEN { history.remove(0); }
                     }
+                    callback(self, &buffer, key);
+                    break
                 },
                 Key::Escape       => {
                     callback(self, &buffer, key);
@@ -473,55 +1336,135 @@ impl Editor {
         match key {
             ArrowKey::Left  => {
                 if self.cursor.x > 0 {
-                    self.cursor.x -= 1
+                    let line = self.buffer.line(self.cursor.y);
+                    self.cursor.x = util::prev_grapheme_boundary(line, self.cursor.x);
                 } else if self.cursor.y > 0 {
                     self.cursor.y -= 1;
                     self.cursor.x = self.current_row_size().unwrap();
                 }
             },
             ArrowKey::Right => {
-                match self.current_row_size() {
-                    Some(current_row_size) => {
-                        if self.cursor.x < current_row_size {
-                            self.cursor.x += 1
-                        } else if self.cursor.x == current_row_size {
-                            self.cursor.y += 1;
-                            self.cursor.x = 0;
-                        }
-                    },
-                    None => ()
+                if let Some(current_row_size) = self.current_row_size() {
+                    if self.cursor.x < current_row_size {
+                        let line = self.buffer.line(self.cursor.y);
+                        self.cursor.x = util::next_grapheme_boundary(line, self.cursor.x);
+                    } else if self.cursor.x == current_row_size {
+                        self.cursor.y += 1;
+                        self.cursor.x = 0;
+                    }
                 }
             },
+            // Moving between rows keeps to the same screen column, since the
+            // same byte offset could land anywhere on a different row.
+            ArrowKey::Up if self.soft_wrap => self.move_screen_line(false),
+            ArrowKey::Down if self.soft_wrap => self.move_screen_line(true),
             ArrowKey::Up    => {
-                if self.cursor.y > 0 { self.cursor.y -= 1 }
+                if self.cursor.y > 0 {
+                    let rx = self.rendered_cursor_x();
+                    self.cursor.y -= 1;
+                    self.cursor.x = self.current_row().map_or(0, |row| row.raw_cursor_x(rx));
+                }
             },
             ArrowKey::Down  => {
-                if self.cursor.y < self.rows.len() {
-                    self.cursor.y += 1
+                if self.cursor.y < self.buffer.len() {
+                    let rx = self.rendered_cursor_x();
+                    self.cursor.y += 1;
+                    self.cursor.x = self.current_row().map_or(0, |row| row.raw_cursor_x(rx));
                 }
             },
         }
-        let current_row_size = self.current_row_size().unwrap_or(0);
-        if (self.cursor.x) > current_row_size {
-            self.cursor.x = current_row_size;
+        if !self.cursor_past_end() {
+            let line = self.buffer.line(self.cursor.y);
+            self.cursor.x = util::floor_grapheme_boundary(line, self.cursor.x);
+        } else {
+            self.cursor.x = 0;
         }
     }
 
+    // Up and Down in soft-wrap mode go by screen line rather than by row,
+    // keeping to the same column within the line.
+    fn move_screen_line(&mut self, down: bool) {
+        let (seg, starts) = self.cursor_segment();
+        let col = self.rendered_cursor_x() - starts[seg];
+        let (y, seg) = if down {
+            if seg + 1 < starts.len() {
+                (self.cursor.y, seg + 1)
+            } else if self.cursor.y < self.buffer.len() {
+                (self.cursor.y + 1, 0)
+            } else {
+                return
+            }
+        } else if seg > 0 {
+            (self.cursor.y, seg - 1)
+        } else if self.cursor.y > 0 {
+            (self.cursor.y - 1, self.wrap_points(self.cursor.y - 1).len() - 1)
+        } else {
+            return
+        };
+        self.cursor.y = y;
+        let width = self.text_cols();
+        self.cursor.x = self.current_row().map_or(0, |row| {
+            let starts = row.wrap_points(width);
+            let mut rx = starts[seg] + col;
+            if let Some(&next) = starts.get(seg + 1) { rx = cmp::min(rx, next - 1) }
+            row.raw_cursor_x(rx)
+        });
+    }
+
     pub fn process_keypress(&mut self) {
         let key = Self::read_key();
         if key.is_none() { return }
-        match key.unwrap() {
+        let key = key.unwrap();
+        // Only the key straight after a paste can cycle it
+        let pasted = self.pasted.take();
+        if self.results.is_some() && Self::is_edit(&key) {
+            self.set_status_message("Search results are read-only; Enter opens a match");
+            return;
+        }
+        match key {
+            Key::Arrow(_) | Key::Home | Key::End | Key::PageUp | Key::PageDown
+                if self.shift_selecting => self.mark = None,
+            _ => (),
+        }
+        match key {
             Key::Character(c) => self.insert_char(c),
+            Key::Control('@') => self.toggle_mark(),
+            Key::Control('C') => self.copy(),
+            Key::Control('E') => self.toggle_line_ending(),
             Key::Control('F') => self.find(),
+            Key::Control('G') => self.search_in_files(),
+            Key::Control('M') if self.results.is_some() => self.open_result(),
             Key::Control('M') => self.insert_newline(),
-            Key::Control('S') => self.save_file(),
+            Key::Control('N') => self.find_again(false),
+            Key::Control('P') => self.find_again(true),
+            Key::Control('S') => self.save(),
+            Key::Control('V') => self.paste(),
+            Key::Control('X') => self.cut(),
+            Key::Control('Y') => self.redo(),
+            Key::Control('Z') => terminal::suspend(),
+            Key::Control('_') => self.undo(),
+            Key::Control('R') => self.replace(),
             Key::Control('Q') => {
                 self.exit();
                 return;
             },
             Key::Control(_)   => (),
+            Key::Alt('n')     => self.toggle_line_numbers(),
+            Key::Alt('w')     => self.toggle_soft_wrap(),
+            Key::Alt('y')     => self.cycle_paste(pasted),
+            Key::Alt(_)       => (),
             Key::Arrow(a)     => self.move_cursor(a),
-            Key::Escape       => (),
+            Key::ShiftArrow(a) => {
+                if self.mark.is_none() {
+                    self.mark = Some(self.cursor);
+                    self.shift_selecting = true;
+                }
+                self.move_cursor(a);
+            },
+            Key::Escape       => {
+                self.search_state.pattern = None;
+                self.mark = None;
+            },
             Key::Backspace    => self.delete_char(),
             Key::Delete       => {
                 self.move_cursor(ArrowKey::Right);
@@ -531,24 +1474,89 @@ impl Editor {
             Key::End          => self.cursor.x = self.current_row_size().unwrap_or(0),
             Key::PageUp       => self.page_up(),
             Key::PageDown     => self.page_down(),
+            Key::Paste(text)  => self.insert_text(text),
+            Key::Mouse(event) => self.handle_mouse(event),
         }
         self.quit_times = KILO_QUIT_TIMES;
     }
 
+    // Clicking puts the cursor there and dragging selects from where the
+    // click was.
+    fn handle_mouse(&mut self, event: MouseEvent) {
+        match event.kind {
+            MouseKind::Press => {
+                self.mark = None;
+                self.click(event.x, event.y);
+            },
+            MouseKind::Drag => {
+                if self.mark.is_none() {
+                    self.mark = Some(self.cursor);
+                    self.shift_selecting = false;
+                }
+                self.click(event.x, event.y);
+            },
+            MouseKind::WheelUp => self.scroll_by(-3),
+            MouseKind::WheelDown => self.scroll_by(3),
+            MouseKind::Release | MouseKind::Other => (),
+        }
+    }
+
+    fn click(&mut self, x: usize, y: usize) {
+        // The status and message bars
+        if y >= self.screen_rows as usize { return }
+        let lines = self.screen_lines();
+        self.cursor = match lines.get(y) {
+            Some(&(file_row, _, start, _)) => {
+                let rx = start + x.saturating_sub(self.gutter_width());
+                let x = Row::new(self.buffer.line(file_row)).raw_cursor_x(rx);
+                Cursor { x, y: file_row }
+            },
+            // Below the last row
+            None => self.clamp_to_text(Cursor { x: 0, y: self.buffer.len() }),
+        };
+    }
+
+    // Moves the view by a number of rows, dragging the cursor along if it
+    // would otherwise go off the screen.
+    fn scroll_by(&mut self, rows: isize) {
+        let last = self.buffer.len().saturating_sub(1);
+        self.row_offset = cmp::min(self.row_offset.saturating_add_signed(rows), last);
+        self.wrap_offset = 0;
+        let lines = self.screen_lines();
+        let (first, last) = match (lines.first(), lines.last()) {
+            (Some(first), Some(last)) => (first.0, last.0),
+            _ => return,
+        };
+        let y = cmp::max(first, cmp::min(self.cursor.y, last));
+        if y != self.cursor.y {
+            let rx = self.rendered_cursor_x();
+            self.cursor.y = y;
+            self.cursor.x = Row::new(self.buffer.line(y)).raw_cursor_x(rx);
+        }
+    }
+
+    fn is_edit(key: &Key) -> bool {
+        match *key {
+            Key::Character(_) | Key::Backspace | Key::Delete | Key::Paste(_) => true,
+            Key::Control(c) => "ERSVXY_".contains(c),
+            Key::Alt('y') => true,
+            _ => false,
+        }
+    }
+
     fn exit(&mut self) {
         if self.dirty && self.quit_times > 0 {
             let quit_times = self.quit_times;
             self.set_status_message(&format!("WARNING!!! File has unsaved changes. Press Ctrl-Q {} more times to quit.", quit_times));
             self.quit_times -= 1;
         } else {
-            let _ = io::stdout().write(b"\x1b[2J");
-            let _ = io::stdout().write(b"\x1b[H");
-            let _ = io::stdout().flush();
+            // disable_raw_mode puts the terminal back on the way out
             process::exit(0)
         }
     }
 
     fn page_up(&mut self) {
+        if self.soft_wrap { return self.page_wrapped(false) }
         self.cursor.y = self.row_offset;
         for _ in 0..self.screen_rows {
             self.move_cursor(ArrowKey::Up)
@@ -556,9 +1564,24 @@ impl Editor {
     }
 
     fn page_down(&mut self) {
-        self.cursor.y = cmp::min(self.rows.len(), self.row_offset + (self.screen_rows as usize) - 1);
+        if self.soft_wrap { return self.page_wrapped(true) }
+        self.cursor.y = cmp::min(self.buffer.len(), self.row_offset + (self.screen_rows as usize) - 1);
         for _ in 0..self.screen_rows {
             self.move_cursor(ArrowKey::Down)
         }
     }
+
+    // Goes to the top or bottom line of the screen first, then a screenful
+    // of lines on from there.
+    fn page_wrapped(&mut self, down: bool) {
+        let lines = self.screen_lines();
+        let line = if down { lines.last() } else { lines.first() };
+        if let Some(&(y, _, start, _)) = line {
+            self.cursor.y = y;
+            self.cursor.x = self.current_row().map_or(0, |row| row.raw_cursor_x(start));
+        }
+        for _ in 0..self.screen_rows {
+            self.move_screen_line(down)
+        }
+    }
 }
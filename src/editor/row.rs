@@ -1,6 +1,8 @@
 use editor::syntax::{Flag, Keyword, Syntax};
+use unicode_segmentation::UnicodeSegmentation;
 use util;
 
+use std::cmp;
 use std::iter;
 use std::rc::Rc;
 
@@ -24,11 +26,12 @@ pub enum Highlight {
     String,
     Number,
     Match,
+    Selection,
 }
 
 impl Highlight {
-    pub fn to_color(&self) -> u8 {
-        match *self {
+    pub fn to_color(self) -> u8 {
+        match self {
             Highlight::Normal => 37,
             Highlight::Comment | Highlight::MLComment => 36,
             Highlight::Keyword1 => 33,
@@ -36,13 +39,15 @@ impl Highlight {
             Highlight::String => 35,
             Highlight::Number => 31,
             Highlight::Match  => 34,
+            // Reverse video
+            Highlight::Selection => 7,
         }
     }
 
     pub fn from_keyword(kw: &Keyword) -> Self {
-        match kw {
-            &Keyword::One(_) => Highlight::Keyword1,
-            &Keyword::Two(_) => Highlight::Keyword2,
+        match *kw {
+            Keyword::One(_) => Highlight::Keyword1,
+            Keyword::Two(_) => Highlight::Keyword2,
         }
     }
 }
@@ -54,9 +59,9 @@ enum InString {
 
 impl InString {
     fn to_char(&self) -> char {
-        match self {
-            &InString::SingleQuoted => '\'',
-            &InString::DoubleQuoted => '"',
+        match *self {
+            InString::SingleQuoted => '\'',
+            InString::DoubleQuoted => '"',
         }
     }
 
@@ -69,26 +74,35 @@ impl InString {
     }
 }
 
-fn is_separator(c: char) -> bool {
+// The column just past grapheme g, if it starts at column rx.
+fn advance(rx: usize, g: &str) -> usize {
+    if g == "\t" {
+        rx + KILO_TAB_STOP - (rx % KILO_TAB_STOP)
+    } else {
+        rx + util::grapheme_width(g)
+    }
+}
+
+fn starts_with_at(chars: &[char], i: usize, pat: &str) -> bool {
+    pat.chars().enumerate().all(|(k, p)| chars.get(i + k) == Some(&p))
+}
+
+pub fn is_separator(c: char) -> bool {
     c.is_whitespace() ||
         c == '\0' ||
         ",.()+-/*=~%<>[];".contains(c)
 }
 
 impl Row {
-    pub fn new() -> Self {
-        Row {
-            contents: String::new(),
-            render: String::new(),
+    pub fn new(contents: &str) -> Self {
+        let mut row = Row {
+            contents: contents.to_string(),
+            render: Self::render_string(contents),
             highlight: Vec::new(),
             syntax: None,
             open_comment: false,
-        }
-    }
-
-    pub fn from_string(s: String) -> Self {
-        let mut row = Self::new();
-        row.append_string(&s);
+        };
+        row.update_syntax();
         row
     }
 
@@ -97,12 +111,12 @@ impl Row {
         self.update_syntax();
     }
 
-    fn update(&mut self) {
-        self.update_render();
-        self.update_syntax();
+    // Whether the row after this one starts inside a multiline comment.
+    pub fn leaves_comment_open(&self) -> bool {
+        self.starts_mlcomment() || (self.open_comment && !self.ends_mlcomment())
     }
 
-    pub fn starts_mlcomment(&self) -> bool {
+    fn starts_mlcomment(&self) -> bool {
         match self.highlight.last() {
             None => false,
             Some(hl) => {
@@ -116,7 +130,7 @@ impl Row {
         }
     }
 
-    pub fn ends_mlcomment(&self) -> bool {
+    fn ends_mlcomment(&self) -> bool {
         match self.highlight.last() {
             None => false,
             Some(hl) => {
@@ -128,9 +142,9 @@ impl Row {
         }
     }
 
-    pub fn update_syntax(&mut self) {
-        self.highlight = iter::repeat(Highlight::Normal)
-            .take(self.render.chars().count()).collect();
+    fn update_syntax(&mut self) {
+        self.highlight = iter::repeat_n(Highlight::Normal, self.render.chars().count())
+            .collect();
 
         if self.syntax.is_none() { return }
         let syntax = self.syntax.as_ref().unwrap();
@@ -145,14 +159,10 @@ impl Row {
         let opens_comment = (!scs.is_empty() && self.render.contains(scs)) ||
             (!mcs.is_empty() && self.render.contains(mcs));
 
-        let mut iter = self.render.chars().enumerate();
-
-        let render = self.render.clone();
-        let keyword_matches = syntax.keywords.iter().flat_map(|&kw| {
-            render.match_indices(kw.as_str())
-                .map(|pair| pair.0)
-                .zip(iter::repeat(kw))
-        }).collect::<Vec<(usize, Keyword)>>();
+        // Everything below works in chars, since that's what highlight is
+        // indexed by.
+        let chars = self.render.chars().collect::<Vec<char>>();
+        let mut iter = chars.iter().cloned().enumerate();
 
         while let Some((i, c)) = iter.next() {
             let prev_hl = if i > 0 {
@@ -161,22 +171,21 @@ impl Row {
                 Highlight::Normal
             };
 
-            if in_string.is_none() && !in_mlcomment && opens_comment {
-                if self.render.chars().skip(i).collect::<String>().starts_with(scs) {
-                    for j in i..self.highlight.len() {
-                        self.highlight[j] = Highlight::Comment;
-                    }
-                    break;
+            if in_string.is_none() && !in_mlcomment && opens_comment
+                && starts_with_at(&chars, i, scs) {
+                for j in i..self.highlight.len() {
+                    self.highlight[j] = Highlight::Comment;
                 }
+                break;
             }
 
             if in_string.is_none() && !mcs.is_empty() && !mce.is_empty() {
                 if in_mlcomment {
-                    match self.render.chars().skip(i).collect::<String>().find(mce) {
+                    match (i..chars.len()).find(|&j| starts_with_at(&chars, j, mce)) {
                         Some(j) => {
                             self.highlight[i] = Highlight::MLComment;
-                            for k in 1..j + mce.len() {
-                                self.highlight[i + k] = Highlight::MLComment;
+                            for k in i + 1..j + mce.chars().count() {
+                                self.highlight[k] = Highlight::MLComment;
                                 iter.next();
                             }
                             in_mlcomment = false;
@@ -189,16 +198,14 @@ impl Row {
                             break;
                         },
                     }
-                } else if opens_comment {
-                    if self.render.chars().skip(i).collect::<String>().starts_with(mcs) {
-                        self.highlight[i] = Highlight::MLComment;
-                        for j in 1..mcs.len() {
-                            self.highlight[i + j] = Highlight::MLComment;
-                            iter.next();
-                        }
-                        in_mlcomment = true;
-                        continue;
+                } else if opens_comment && starts_with_at(&chars, i, mcs) {
+                    self.highlight[i] = Highlight::MLComment;
+                    for j in 1..mcs.chars().count() {
+                        self.highlight[i + j] = Highlight::MLComment;
+                        iter.next();
                     }
+                    in_mlcomment = true;
+                    continue;
                 }
             }
 
@@ -216,12 +223,9 @@ impl Row {
                         self.highlight[i] = Highlight::String;
 
                         if c == '\\' {
-                            match iter.next() {
-                                Some((j, _)) => {
-                                    self.highlight[j] = Highlight::String;
-                                    continue;
-                                },
-                                None => (),
+                            if let Some((j, _)) = iter.next() {
+                                self.highlight[j] = Highlight::String;
+                                continue;
                             }
                         }
 
@@ -232,101 +236,134 @@ impl Row {
                 }
             }
 
-            if syntax.flags.contains(&Flag::HighlightNumbers) {
-                if (c.is_digit(10) && (prev_sep || prev_hl == Highlight::Number)) || (c == '.' && prev_hl == Highlight::Number) {
-                    prev_sep = false;
-                    self.highlight[i] = Highlight::Number;
-                    continue;
-                }
+            if syntax.flags.contains(&Flag::HighlightNumbers)
+                && ((c.is_ascii_digit() && (prev_sep || prev_hl == Highlight::Number)) || (c == '.' && prev_hl == Highlight::Number)) {
+                prev_sep = false;
+                self.highlight[i] = Highlight::Number;
+                continue;
             }
 
             if prev_sep {
-                match keyword_matches.iter().find(|&&(j, _)| { i == j }) {
-                    None => (),
-                    Some(&(_, kw)) => {
-                        let s = kw.as_str();
-                        let chars = self.render.chars().collect::<Vec<char>>();
-                        let separated = i + s.len() == self.render.len() ||
-                            is_separator(chars[i + s.len()]);
-                        if !separated { continue }
-                        let hl = Highlight::from_keyword(&kw);
-                        self.highlight[i] = hl;
-                        for j in 1..s.len() {
-                            self.highlight[i + j] = hl;
-                            iter.next();
-                        }
-                        continue;
-                    },
-                };
+                let keyword = syntax.keywords.iter().find(|kw| {
+                    let len = kw.as_str().chars().count();
+                    starts_with_at(&chars, i, kw.as_str()) &&
+                        chars.get(i + len).is_none_or(|&c| is_separator(c))
+                });
+                if let Some(kw) = keyword {
+                    let hl = Highlight::from_keyword(kw);
+                    self.highlight[i] = hl;
+                    for j in 1..kw.as_str().chars().count() {
+                        self.highlight[i + j] = hl;
+                        iter.next();
+                    }
+                    continue;
+                }
             }
 
             prev_sep = is_separator(c);
         }
     }
 
-    pub fn insert_char(&mut self, at: usize, c: char) {
-        self.contents.insert(at, c);
-        self.update();
-    }
-
-    pub fn delete_char(&mut self, at: usize) {
-        if at >= self.contents.len() { return }
-        self.contents.remove(at);
-        self.update();
+    // Takes byte offsets into contents, mapped through render_indices.
+    pub fn highlight_range(&mut self, indices: &[usize], start: usize, end: usize,
+                           hl: Highlight) {
+        for h in &mut self.highlight[indices[start]..indices[end]] { *h = hl }
     }
 
-    pub fn append_string(&mut self, s: &str) {
-        self.contents.push_str(s);
-        self.update();
+    // For each byte offset into contents, the index into render (and
+    // highlight) of the char it ended up as. Worked out in one go so that
+    // highlighting every match on a long row doesn't render it over and over.
+    pub fn render_indices(&self) -> Vec<usize> {
+        let mut indices = Vec::with_capacity(self.contents.len() + 1);
+        let mut rx = 0;
+        let mut index = 0;
+        for g in self.contents.graphemes(true) {
+            let next = advance(rx, g);
+            if g == "\t" {
+                // Rendered as however many spaces it takes to the next stop
+                indices.push(index);
+                index += next - rx;
+            } else {
+                for c in g.chars() {
+                    indices.extend(iter::repeat_n(index, c.len_utf8()));
+                    index += 1;
+                }
+            }
+            rx = next;
+        }
+        indices.push(index);
+        indices
     }
 
-    pub fn split_off(&mut self, at: usize) -> String {
-        let remainder = util::safe_split_off(&mut self.contents, at);
-        self.update();
-        remainder
+    pub fn rendered_cursor_x(&self, cursor_x: usize) -> usize {
+        self.contents[..cursor_x].graphemes(true).fold(0, advance)
     }
 
-    pub fn rendered_cursor_x(&self, cursor_x: usize) -> usize {
-        self.contents.chars()
-            .take(cursor_x)
-            .fold(0, |acc, c| {
-                if c == '\t' {
-                    acc + KILO_TAB_STOP - (acc % KILO_TAB_STOP)
-                } else {
-                    acc + 1
-                }
-        })
+    pub fn cursor_width(&self, cursor_x: usize) -> usize {
+        match self.contents[cursor_x..].graphemes(true).next() {
+            Some(g) if g != "\t" => cmp::max(1, util::grapheme_width(g)),
+            _ => 1,
+        }
     }
 
     pub fn raw_cursor_x(&self, rendered_x: usize) -> usize {
-        self.contents.chars()
-            .scan(0, |acc, c| {
-                if c == '\t' {
-                    *acc = *acc + KILO_TAB_STOP - (*acc % KILO_TAB_STOP)
-                } else {
-                    *acc += 1
-                };
-                Some(*acc)
-            }).position(|rx| rx > rendered_x).unwrap()
+        let mut rx = 0;
+        for (i, g) in self.contents.grapheme_indices(true) {
+            rx = advance(rx, g);
+            if rx > rendered_x { return i }
+        }
+        self.contents.len()
     }
 
-    fn update_render(&mut self) {
-        self.render = Self::render_string(self.contents.clone());
+    // The columns of render that each screen line starts at when the row is
+    // wrapped to width. A row that fills its last line exactly gets an empty
+    // one after it, so the cursor has somewhere to go at the end.
+    pub fn wrap_points(&self, width: usize) -> Vec<usize> {
+        let mut starts = vec![0];
+        let mut start = 0;
+        let mut rx = 0;
+        for g in self.render.graphemes(true) {
+            let next = rx + util::grapheme_width(g);
+            if next > start + width && rx > start {
+                start = rx;
+                starts.push(start);
+            }
+            rx = next;
+        }
+        if rx >= start + width { starts.push(rx) }
+        starts
     }
 
-    fn render_string(s: String) -> String {
-        let mut idx = 0;
-        let renderer = |c|
-            if c == '\t' {
-                let n = KILO_TAB_STOP - (idx % KILO_TAB_STOP);
-                idx += n;
-                iter::repeat(' ').take(n)
+    fn render_string(s: &str) -> String {
+        let mut rx = 0;
+        let mut render = String::new();
+        for g in s.graphemes(true) {
+            let next = advance(rx, g);
+            if g == "\t" {
+                render.push_str(&" ".repeat(next - rx));
             } else {
-                idx += 1;
-                // This is the same as iter::once(c), but the types of
-                // the branches of the conditional have to line up.
-                iter::repeat(c).take(1)
-            };
-        s.chars().flat_map(renderer).collect()
+                render.push_str(g);
+            }
+            rx = next;
+        }
+        render
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_indices_match_rendering_each_prefix() {
+        for &s in &["plain", "a\tb\t\tc", "中\tx", "e\u{301}\tñ", "\x01\tz", ""] {
+            let row = Row::new(s);
+            let indices = row.render_indices();
+            assert_eq!(indices.len(), s.len() + 1);
+            for (x, _) in s.char_indices().chain(Some((s.len(), ' '))) {
+                let expected = Row::render_string(&s[..x]).chars().count();
+                assert_eq!(indices[x], expected, "{:?} at {}", s, x);
+            }
+        }
     }
 }
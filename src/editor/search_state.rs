@@ -1,5 +1,6 @@
 use editor::cursor::Cursor;
-use editor::row::Highlight;
+use editor::row;
+use regex::{self, Regex, RegexBuilder};
 
 #[derive(PartialEq, Eq, Clone, Copy)]
 pub enum Direction {
@@ -7,14 +8,60 @@ pub enum Direction {
     Backward,
 }
 
+impl Direction {
+    pub fn reverse(self) -> Self {
+        match self {
+            Direction::Forward => Direction::Backward,
+            Direction::Backward => Direction::Forward,
+        }
+    }
+}
+
 pub struct SearchState {
     pub last_match: Option<Match>,
     pub direction: Direction,
+    pub error: Option<String>,
+    // Every match of this gets highlighted
+    pub pattern: Option<Pattern>,
+    pub match_count: usize,
+    pub match_index: usize,
+    // Whether getting to the current match went past one end of the file
+    pub wrapped: bool,
+}
+
+// These outlive a single search, unlike SearchState.
+#[derive(Clone, Copy)]
+pub struct SearchOptions {
+    pub regex: bool,
+    pub case: CaseMode,
+    pub whole_word: bool,
+    pub keep_highlights: bool,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum CaseMode {
+    Sensitive,
+    Insensitive,
+    // Sensitive only if the query has an uppercase letter in it
+    Smart,
+}
+
+#[derive(Clone)]
+pub struct Pattern {
+    pub regex: Regex,
+    whole_word: bool,
+}
+
+pub struct Matches<'a> {
+    pattern: &'a Pattern,
+    line: &'a str,
+    x: Option<usize>,
 }
 
+#[derive(Clone, Copy)]
 pub struct Match {
     pub cursor: Cursor,
-    pub highlight: Vec<Highlight>,
+    pub len: usize,
 }
 
 impl SearchState {
@@ -22,6 +69,154 @@ impl SearchState {
         SearchState {
             last_match: None,
             direction: Direction::Forward,
+            error: None,
+            pattern: None,
+            match_count: 0,
+            match_index: 0,
+            wrapped: false,
+        }
+    }
+}
+
+impl CaseMode {
+    pub fn next(self) -> Self {
+        match self {
+            CaseMode::Sensitive => CaseMode::Insensitive,
+            CaseMode::Insensitive => CaseMode::Smart,
+            CaseMode::Smart => CaseMode::Sensitive,
+        }
+    }
+}
+
+impl SearchOptions {
+    pub fn new() -> Self {
+        SearchOptions {
+            regex: false,
+            case: CaseMode::Sensitive,
+            whole_word: false,
+            keep_highlights: false,
+        }
+    }
+
+    // Plain queries are escaped, so either way the search runs on a regex.
+    pub fn compile(&self, query: &str) -> Result<Pattern, String> {
+        let pattern = if self.regex {
+            query.to_string()
+        } else {
+            regex::escape(query)
+        };
+        let ignore_case = match self.case {
+            CaseMode::Sensitive => false,
+            CaseMode::Insensitive => true,
+            CaseMode::Smart => !has_uppercase(query, self.regex),
+        };
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|e| describe_error(&e))?;
+        Ok(Pattern { regex, whole_word: self.whole_word })
+    }
+
+    // The options that are switched on, for showing in the prompt.
+    pub fn describe(&self) -> String {
+        let mut options = Vec::new();
+        if self.regex { options.push("regex") }
+        match self.case {
+            CaseMode::Sensitive => (),
+            CaseMode::Insensitive => options.push("ignore case"),
+            CaseMode::Smart => options.push("smart case"),
         }
+        if self.whole_word { options.push("whole word") }
+        if self.keep_highlights { options.push("keep highlights") }
+        options.join(", ")
+    }
+}
+
+impl Pattern {
+    // The start and end of the first match in line at or after byte x.
+    // Whole words are bounded by the same separators syntax highlighting
+    // uses, which aren't quite what \b means.
+    pub fn find_at(&self, line: &str, x: usize) -> Option<(usize, usize)> {
+        let mut x = x;
+        loop {
+            let m = self.regex.find_at(line, x)?;
+            if !self.whole_word || is_whole_word(line, m.start(), m.end()) {
+                return Some((m.start(), m.end()))
+            }
+            // The next candidate might overlap this one
+            x = m.start() + line[m.start()..].chars().next()?.len_utf8();
+        }
+    }
+
+    pub fn matches<'a>(&'a self, line: &'a str) -> Matches<'a> {
+        Matches { pattern: self, line, x: Some(0) }
+    }
+}
+
+impl<'a> Iterator for Matches<'a> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        let (start, end) = self.pattern.find_at(self.line, self.x?)?;
+        // An empty match has to be stepped over, or it would be found again
+        self.x = if end > start {
+            Some(end)
+        } else {
+            self.line[end..].chars().next().map(|c| end + c.len_utf8())
+        };
+        Some((start, end))
+    }
+}
+
+fn is_whole_word(line: &str, start: usize, end: usize) -> bool {
+    line[..start].chars().next_back().is_none_or(row::is_separator) &&
+        line[end..].chars().next().is_none_or(row::is_separator)
+}
+
+// Whether the query asks for any uppercase text. In a regex, the letters
+// that make up escapes like \W or \p{Lu} don't count.
+fn has_uppercase(query: &str, regex: bool) -> bool {
+    if !regex { return query.chars().any(char::is_uppercase) }
+    let mut chars = query.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            if c.is_uppercase() { return true }
+            continue;
+        }
+        match chars.next() {
+            // A class or code point name, either braced or a letter or two
+            Some(c @ 'p') | Some(c @ 'P') | Some(c @ 'x') => {
+                if chars.peek() == Some(&'{') {
+                    chars.by_ref().find(|&c| c == '}');
+                } else {
+                    chars.by_ref().take(if c == 'x' { 2 } else { 1 }).count();
+                }
+            },
+            Some(_) | None => (),
+        }
+    }
+    false
+}
+
+// Syntax errors draw the pattern out over several lines; the last one says
+// what's actually wrong.
+fn describe_error(e: &regex::Error) -> String {
+    let msg = e.to_string();
+    let last = msg.lines().last().unwrap_or("");
+    last.trim_start_matches("error: ").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smart_case_ignores_uppercase_escapes_in_regexes() {
+        assert!(!has_uppercase(r"\W+foo\S\D\B", true));
+        assert!(!has_uppercase(r"\p{Lu}\pL\P{Greek}\x4F\x{1F600}", true));
+        assert!(has_uppercase(r"\WFoo", true));
+        assert!(has_uppercase(r"\\W", true));
+        assert!(has_uppercase(r"\W", false));
+        assert!(!has_uppercase("foo", false));
     }
 }
@@ -27,17 +27,16 @@ pub enum Keyword {
 
 impl Keyword {
     pub fn as_str(&self) -> &'static str {
-        match self {
-            &Keyword::One(s) => s,
-            &Keyword::Two(s) => s,
+        match *self {
+            Keyword::One(s) => s,
+            Keyword::Two(s) => s,
         }
     }
 }
 
 impl Syntax {
     fn database() -> Vec<Self> {
-        let mut db = Vec::new();
-        db.push(Syntax {
+        vec![Syntax {
             filetype: "c",
             filematch: vec![".c", ".h", ".cpp"],
             keywords: vec![
@@ -58,8 +57,7 @@ impl Syntax {
                 Flag::HighlightNumbers,
                 Flag::HighlightStrings,
             ].iter().cloned().collect(),
-        });
-        db.push(Syntax {
+        }, Syntax {
             filetype: "rust",
             filematch: vec![".rs"],
             keywords: vec![
@@ -84,8 +82,7 @@ impl Syntax {
                 Flag::HighlightNumbers,
                 Flag::HighlightStrings,
             ].iter().cloned().collect(),
-        });
-        db
+        }]
     }
 
     pub fn for_filename(filename: &str) -> Option<Self> {
@@ -97,7 +94,7 @@ impl Syntax {
             match res {
                 Some((match_idx, Some(name_idx))) => {
                     let matched = s.filematch[match_idx];
-                    if matched.chars().next().unwrap() == '.' ||
+                    if matched.starts_with('.') ||
                         name_idx + matched.len() == filename.len() {
                         return Some(s)
                     }
@@ -1,25 +1,52 @@
+extern crate ignore;
 extern crate libc;
+extern crate regex;
+extern crate unicode_segmentation;
+extern crate unicode_width;
 
+mod clipboard;
 mod editor;
 mod terminal;
 mod util;
 
 use std::env;
+use std::io;
+use std::panic;
+use std::process;
 
 fn main() {
     terminal::enable_raw_mode();
+    terminal::restore_on_panic();
+    terminal::watch_window_size();
+    terminal::watch_resume();
+    terminal::watch_fatal_signals();
     let mut editor = editor::Editor::new();
 
+    editor.set_status_message("HELP: Ctrl-S = save | Ctrl-Q = quit | Ctrl-F = find | Ctrl-G = grep | Ctrl-N/Ctrl-P = next/prev | Ctrl-R = replace | Ctrl-X/C/V = cut/copy/paste | Ctrl-_/Ctrl-Y = undo/redo | Ctrl-Z = suspend");
+
     let mut args = env::args();
     if args.len() >= 2 {
         let filename = args.nth(1).unwrap();
-        editor.open_file(&filename);
+        match editor.open_file(&filename) {
+            Ok(()) => (),
+            // Opening a file that doesn't exist yet starts a new one
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                editor.set_filename(filename);
+            },
+            Err(e) => {
+                let msg = format!("Can't open {}: {}", filename, util::describe_io_error(&e));
+                editor.set_status_message(&msg);
+            },
+        }
     }
 
-    editor.set_status_message("HELP: Ctrl-S = save | Ctrl-Q = quit | Ctrl-F = find");
-
-    loop {
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| loop {
         editor.refresh_screen();
         editor.process_keypress();
+    }));
+    // The panic hook has already restored the terminal and said what happened
+    if result.is_err() {
+        editor.rescue();
+        process::exit(101);
     }
 }
@@ -1,28 +1,71 @@
 use libc;
+use util;
 
 use std::io::{self, Read, Write};
 use std::mem;
+use std::panic;
 use std::str;
+use std::sync::Once;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 static mut ORIG_TERMIOS: Option<libc::termios> = None;
+static AT_EXIT: Once = Once::new();
+static RAW_MODE: AtomicBool = AtomicBool::new(false);
+static WINDOW_RESIZED: AtomicBool = AtomicBool::new(false);
+static RESUMED: AtomicBool = AtomicBool::new(false);
+static FATAL_SIGNAL: AtomicUsize = AtomicUsize::new(0);
+
+// Terminals cap how much they'll take in one OSC 52 sequence, and some drop
+// the whole thing if it's over. 100000 bytes of base64 gets through most.
+const OSC52_LIMIT: usize = 100_000;
 
 extern "C" fn disable_raw_mode() {
+    restore();
+}
+
+// Puts the terminal back the way we found it. This runs from the panic hook
+// and on the way out after a signal, so it never panics and only does
+// anything the first time.
+pub fn restore() {
+    if !RAW_MODE.swap(false, Ordering::SeqCst) { return }
     unsafe {
-        let mut termios = ORIG_TERMIOS.unwrap();
-        let errno = libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &mut termios);
-        if errno == -1 { panic!("tcsetattr") }
+        if let Some(termios) = ORIG_TERMIOS {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &termios);
+        }
     }
+    let mut stdout = io::stdout();
+    // Leaving the alternate screen brings back whatever was there before
+    let _ = stdout.write(b"\x1b[?1006l\x1b[?1002l\x1b[?2004l\x1b[?25h\x1b[?1049l");
+    let _ = stdout.flush();
 }
 
+pub fn restore_on_panic() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore();
+        default_hook(info);
+    }));
+}
+
+// Safe to call again after being stopped, whether or not the shell put the
+// terminal back in cooked mode in the meantime.
 pub fn enable_raw_mode() {
     unsafe {
-        let mut termios: libc::termios = mem::zeroed();
+        if !RAW_MODE.load(Ordering::SeqCst) {
+            let mut termios: libc::termios = mem::zeroed();
+
+            let errno = libc::tcgetattr(libc::STDIN_FILENO, &mut termios as *mut libc::termios);
+            if errno == -1 { panic!("tcgetattr") }
 
-        let errno = libc::tcgetattr(libc::STDIN_FILENO, &mut termios as *mut libc::termios);
-        if errno == -1 { panic!("tcgetattr") }
+            ORIG_TERMIOS = Some(termios);
+            AT_EXIT.call_once(|| { libc::atexit(disable_raw_mode); });
 
-        ORIG_TERMIOS = Some(termios);
-        libc::atexit(disable_raw_mode);
+            // Bracketed paste too, so pasting doesn't look like typing, and
+            // SGR mouse reports for presses, drags and the wheel
+            let _ = io::stdout().write(b"\x1b[?1049h\x1b[?2004h\x1b[?1002h\x1b[?1006h");
+            let _ = io::stdout().flush();
+        }
+        let mut termios = ORIG_TERMIOS.unwrap();
 
         termios.c_iflag &= !(libc::BRKINT | libc::ICRNL | libc::INPCK | libc::ISTRIP | libc::IXON);
         termios.c_oflag &= !libc::OPOST;
@@ -31,9 +74,89 @@ pub fn enable_raw_mode() {
         termios.c_cc[libc::VMIN] = 0;
         termios.c_cc[libc::VTIME] = 1;
 
-        let errno = libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &mut termios);
+        let errno = libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &termios);
         if errno == -1 { panic!("tcsetattr") }
     }
+    RAW_MODE.store(true, Ordering::SeqCst);
+}
+
+// Signal handlers only set flags; the editor checks them between keypresses.
+fn set_handler(signal: libc::c_int, handler: extern "C" fn(libc::c_int)) {
+    unsafe {
+        libc::signal(signal, handler as *const () as libc::sighandler_t);
+    }
+}
+
+extern "C" fn handle_sigwinch(_: libc::c_int) {
+    WINDOW_RESIZED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn handle_sigcont(_: libc::c_int) {
+    RESUMED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn handle_fatal_signal(signal: libc::c_int) {
+    FATAL_SIGNAL.store(signal as usize, Ordering::SeqCst);
+}
+
+pub fn watch_window_size() {
+    set_handler(libc::SIGWINCH, handle_sigwinch);
+}
+
+pub fn watch_resume() {
+    set_handler(libc::SIGCONT, handle_sigcont);
+}
+
+// Hands the terminal back to the shell and stops, then takes it back once
+// we're running again. raise only returns after that, or straight away if
+// the signal was thrown away, as it is without job control, and then
+// there's no SIGCONT to wait for.
+pub fn suspend() {
+    restore();
+    unsafe {
+        libc::raise(libc::SIGTSTP);
+    }
+    enable_raw_mode();
+    // Either way the screen needs drawing again
+    RESUMED.store(true, Ordering::SeqCst);
+}
+
+// SIGINT can't come from the keyboard in raw mode, but kill can still send it.
+pub fn watch_fatal_signals() {
+    for &signal in &[libc::SIGHUP, libc::SIGINT, libc::SIGTERM] {
+        set_handler(signal, handle_fatal_signal);
+    }
+}
+
+// Whether the window has been resized since this was last called.
+pub fn window_resized() -> bool {
+    WINDOW_RESIZED.swap(false, Ordering::SeqCst)
+}
+
+// Whether we've been continued after being stopped since this was last
+// called, which leaves the screen needing a redraw.
+pub fn resumed() -> bool {
+    RESUMED.swap(false, Ordering::SeqCst)
+}
+
+// The signal we've been asked to quit by, if any.
+pub fn fatal_signal() -> Option<i32> {
+    match FATAL_SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal as i32),
+    }
+}
+
+// Asks the terminal to put text on the system clipboard with OSC 52, which
+// works over ssh and inside tmux too. There's no reply, so the most we can
+// say is whether it was small enough to send.
+pub fn set_clipboard(text: &str) -> bool {
+    let encoded = util::base64(text.as_bytes());
+    if encoded.len() > OSC52_LIMIT { return false }
+    let mut stdout = io::stdout();
+    let _ = write!(stdout, "\x1b]52;c;{}\x07", encoded);
+    let _ = stdout.flush();
+    true
 }
 
 fn get_cursor_position() -> Option<(u16, u16)> {
@@ -41,13 +164,13 @@ fn get_cursor_position() -> Option<(u16, u16)> {
     let _ = io::stdout().flush();
     let mut buffer = [0;32];
     let _ = io::stdin().read(&mut buffer);
-    let mut iter = str::from_utf8(&buffer[2..]).unwrap().split(|c| c == ';');
-    let rows: u16 = iter.next().unwrap()
-        .parse().unwrap();
-    let cols = iter.next().unwrap()
-        .split(|c| c == 'R')
-        .next().unwrap()
-        .parse().unwrap();
+    let mut iter = str::from_utf8(&buffer[2..]).ok()?.split(';');
+    let rows: u16 = iter.next()?
+        .parse().ok()?;
+    let cols = iter.next()?
+        .split('R')
+        .next()?
+        .parse().ok()?;
     Some((rows, cols))
 }
 
@@ -1,11 +1,33 @@
-pub fn safe_truncate(string: &mut String, i: usize) {
-    if string.len() <= i {
-        return
-    } else if string.is_char_boundary(i) {
-        string.truncate(i)
-    } else {
-        safe_truncate(string, i - 1)
-    }
+use libc;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{self as unix_fs, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+
+// Truncates to at most the given number of terminal columns, without
+// splitting a grapheme cluster.
+pub fn truncate_to_width(string: &mut String, width: usize) {
+    let mut used = 0;
+    let end = string.grapheme_indices(true)
+        .find(|&(_, g)| {
+            used += grapheme_width(g);
+            used > width
+        })
+        .map(|(i, _)| i);
+    if let Some(end) = end { string.truncate(end) }
+}
+
+// Control characters are drawn as a single inverted symbol.
+pub fn grapheme_width(g: &str) -> usize {
+    if g.starts_with(char::is_control) { 1 } else { g.width() }
+}
+
+pub fn display_width(s: &str) -> usize {
+    s.graphemes(true).map(grapheme_width).sum()
 }
 
 pub fn safe_split_off(string: &mut String, i: usize) -> String {
@@ -17,3 +39,146 @@ pub fn safe_split_off(string: &mut String, i: usize) -> String {
         safe_split_off(string, i - 1)
     }
 }
+
+// Byte offset of the grapheme cluster boundary before i, which must itself
+// be a boundary.
+pub fn prev_grapheme_boundary(s: &str, i: usize) -> usize {
+    s[..i].graphemes(true).next_back().map_or(0, |g| i - g.len())
+}
+
+pub fn next_grapheme_boundary(s: &str, i: usize) -> usize {
+    s[i..].graphemes(true).next().map_or(s.len(), |g| i + g.len())
+}
+
+// The last grapheme cluster boundary at or before i.
+pub fn floor_grapheme_boundary(s: &str, i: usize) -> usize {
+    s.grapheme_indices(true)
+        .map(|(j, _)| j)
+        .chain(Some(s.len()))
+        .take_while(|&j| j <= i)
+        .last()
+        .unwrap_or(0)
+}
+
+pub fn describe_io_error(e: &io::Error) -> String {
+    match e.raw_os_error() {
+        Some(libc::ENOENT) => "No such file or directory".to_string(),
+        Some(libc::EACCES) | Some(libc::EPERM) => "Permission denied".to_string(),
+        Some(libc::ENOSPC) => "Disk full".to_string(),
+        Some(libc::EDQUOT) => "Disk quota exceeded".to_string(),
+        Some(libc::EROFS) => "Read-only file system".to_string(),
+        Some(libc::EISDIR) => "Is a directory".to_string(),
+        _ => e.to_string(),
+    }
+}
+
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// Writes to a temporary file in the same directory and renames it over the
+// target, so a crash or a full disk part way through a save never leaves a
+// truncated file behind. When that can't be done without losing the file's
+// owner, or at all, it overwrites the file instead and returns false so the
+// user can be told.
+pub fn write_atomically(path: &str, contents: &[u8]) -> io::Result<bool> {
+    // Write through symlinks rather than replacing them
+    let target = match fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => PathBuf::from(path),
+        Err(e) => return Err(e),
+    };
+    let original = fs::metadata(&target).ok();
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = match target.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Err(io::Error::from_raw_os_error(libc::EISDIR)),
+    };
+    let tmp = dir.join(format!(".{}.kilo-{}.tmp", name, process::id()));
+
+    let f = OpenOptions::new().write(true).create_new(true)
+        .mode(original.as_ref().map_or(0o666, |m| m.permissions().mode()))
+        .open(&tmp);
+    let mut f = match f {
+        Ok(f) => f,
+        // A file we can write in a directory we can't
+        Err(ref e) if overwrite_instead(e, original.is_some()) => {
+            write_in_place(&target, contents)?;
+            return Ok(false);
+        },
+        Err(e) => return Err(e),
+    };
+    if let Some(ref m) = original {
+        if unix_fs::fchown(&f, Some(m.uid()), Some(m.gid())).is_err() {
+            // Renaming our copy into place would change who owns the file,
+            // so the only way to keep its ownership is to overwrite it.
+            drop(f);
+            let _ = fs::remove_file(&tmp);
+            write_in_place(&target, contents)?;
+            return Ok(false);
+        }
+    }
+
+    let res = write_temp(&mut f, contents, original.as_ref())
+        .and_then(|_| fs::rename(&tmp, &target));
+    if res.is_err() { let _ = fs::remove_file(&tmp); }
+    res?;
+
+    // Make the rename itself durable
+    if let Ok(d) = File::open(&dir) { let _ = d.sync_all(); }
+    Ok(true)
+}
+
+// Whether failing to create the temporary file still leaves overwriting the
+// target as a way to save. Only a lack of permission does, and only if the
+// target is already there.
+fn overwrite_instead(e: &io::Error, exists: bool) -> bool {
+    exists && matches!(e.raw_os_error(), Some(libc::EACCES) | Some(libc::EPERM))
+}
+
+fn write_temp(f: &mut File, contents: &[u8], original: Option<&fs::Metadata>) -> io::Result<()> {
+    // The mode the file was created with is subject to the umask
+    if let Some(m) = original { f.set_permissions(m.permissions())? }
+    f.write_all(contents)?;
+    f.sync_all()
+}
+
+fn write_in_place(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut f = OpenOptions::new().write(true).truncate(true).open(path)?;
+    f.write_all(contents)?;
+    f.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overwrites_only_existing_files_it_lacks_permission_to_replace() {
+        let denied = io::Error::from_raw_os_error(libc::EACCES);
+        let not_permitted = io::Error::from_raw_os_error(libc::EPERM);
+        let full = io::Error::from_raw_os_error(libc::ENOSPC);
+        let read_only = io::Error::from_raw_os_error(libc::EROFS);
+        assert!(overwrite_instead(&denied, true));
+        assert!(overwrite_instead(&not_permitted, true));
+        assert!(!overwrite_instead(&denied, false));
+        assert!(!overwrite_instead(&full, true));
+        assert!(!overwrite_instead(&read_only, true));
+    }
+}